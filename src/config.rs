@@ -0,0 +1,278 @@
+use serde::Deserialize;
+use std::path::{ Path, PathBuf };
+use std::collections::HashSet;
+use crate::error::Error;
+use crate::source::{ Source, Token };
+use crate::filter::{ Pattern, Expr };
+use crate::git::{ RefSelection, CloneStrategy, MaintenancePolicy };
+
+/// The configuration that can be provided via `--config`. Anything
+/// given on the command line takes precedence over this.
+#[derive(Debug,Default,PartialEq,Eq,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Location to place the backups
+    pub destination: Option<PathBuf>,
+    /// The token to use for any source that doesn't provide its own
+    pub token: Option<Token>,
+    /// Remove backups that no longer correspond to a repository
+    pub prune: Option<bool>,
+    /// How many repositories to sync at once
+    pub jobs: Option<usize>,
+    /// Only back up repositories matching one of these
+    #[serde(default)]
+    pub include: Vec<Pattern>,
+    /// Don't back up repositories matching any of these
+    #[serde(default)]
+    pub exclude: Vec<Pattern>,
+    /// Only back up repositories for which this is true
+    pub filter: Option<Expr>,
+    /// Clone repositories over SSH using this private key, for any
+    /// source that doesn't provide its own
+    pub ssh_key: Option<PathBuf>,
+    /// The known_hosts file to check hosts against when cloning over SSH
+    pub known_hosts: Option<PathBuf>,
+    /// Which refs to back up, for any source that doesn't say
+    pub refs: Option<RefSelection>,
+    /// Fetch LFS objects, for any source that doesn't say
+    pub lfs: Option<bool>,
+    /// Back up submodules, for any source that doesn't say
+    pub submodules: Option<bool>,
+    /// Give up on talking to a remote after this many seconds
+    pub timeout: Option<u64>,
+    /// How many times to retry syncs that fail for reasons that might not last
+    pub retries: Option<u32>,
+    /// Check that each backup is intact once it's been synced
+    pub verify: Option<bool>,
+    /// Share objects between related backups
+    pub share_objects: Option<bool>,
+    /// How to tidy up each backup once it's been synced, and whether to
+    pub maintenance: Option<MaintenancePolicy>,
+    /// Write each backup into a bundle file once it's been synced
+    pub bundle: Option<bool>,
+    /// Place bundles in this folder, rather than alongside the backups
+    pub bundle_dir: Option<PathBuf>,
+    /// Keep a chain of incremental bundles for each backup
+    pub incremental_bundles: Option<bool>,
+    /// How to clone repositories; the first rule whose filter matches
+    /// a repository is used, and the rest are cloned in full
+    #[serde(default, rename = "strategy")]
+    pub strategies: Vec<StrategyRule>,
+    /// The sources to back up
+    #[serde(default, rename = "source")]
+    pub sources: Vec<Source>
+}
+
+impl Config {
+    /// Load and validate a config file. Relative paths in the file are
+    /// taken to be relative to the folder that the file is in.
+    pub fn load(path: &Path) -> Result<Config,Error> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| err!("Could not read config file '{}': {}", path.to_string_lossy(), e))?;
+        let mut config = Config::parse(&contents)
+            .map_err(|e| err!("Invalid config file '{}': {}", path.to_string_lossy(), e))?;
+
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        config.destination = config.destination.map(|p| base.join(p));
+        config.token = config.token.map(|t| resolve_token_path(base, t));
+        config.ssh_key = config.ssh_key.map(|p| base.join(p));
+        config.known_hosts = config.known_hosts.map(|p| base.join(p));
+        config.bundle_dir = config.bundle_dir.map(|p| base.join(p));
+        for source in &mut config.sources {
+            source.destination = source.destination.take().map(|p| base.join(p));
+            source.token = source.token.take().map(|t| resolve_token_path(base, t));
+            source.ssh_key = source.ssh_key.take().map(|p| base.join(p));
+            source.known_hosts = source.known_hosts.take().map(|p| base.join(p));
+        }
+
+        Ok(config)
+    }
+    /// Parse and validate the contents of a config file.
+    fn parse(contents: &str) -> Result<Config,Error> {
+        let config: Config = toml::from_str(contents)?;
+
+        if config.jobs == Some(0) {
+            return Err(err!("'jobs' must be at least 1"))
+        }
+        if config.timeout == Some(0) {
+            return Err(err!("'timeout' must be at least 1"))
+        }
+        if let Some(token) = &config.token {
+            token.validate()?;
+        }
+
+        let mut seen_folders = HashSet::new();
+        for (idx, source) in config.sources.iter().enumerate() {
+            source.validate()
+                .map_err(|e| err!("source {} ('{}'): {}", idx + 1, source.url, e))?;
+            if source.destination.is_none() && !seen_folders.insert(source.folder_name()) {
+                return Err(err!("source {} ('{}'): another source is already backed up to the folder '{}'; give it a different 'name'",
+                    idx + 1, source.url, source.folder_name()))
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// Clone repositories for which the filter is true using the strategy given.
+#[derive(Debug,Clone,PartialEq,Eq,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StrategyRule {
+    pub filter: Expr,
+    pub strategy: CloneStrategy
+}
+
+fn resolve_token_path(base: &Path, token: Token) -> Token {
+    match token {
+        Token::File(path) => Token::File(base.join(path)),
+        token => token
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(r#"
+            destination = "/backups"
+            token = { env = "GIT_TOKEN" }
+            prune = true
+            jobs = 4
+            exclude = ["tmp-*", "re:^experiment"]
+            filter = "!archived && age < 3y"
+            known_hosts = "known_hosts"
+            refs = "branches-and-tags"
+            lfs = true
+            submodules = true
+            timeout = 600
+            retries = 3
+            verify = true
+            share_objects = true
+            bundle = true
+            bundle_dir = "/exports"
+            incremental_bundles = true
+
+            [maintenance]
+            enabled = true
+            commit_graph = false
+            gc_days = 14
+
+            [[strategy]]
+            filter = "size > 10GB"
+            strategy = "blob-limit=1MB"
+
+            [[strategy]]
+            filter = "name ~ huge-*"
+            strategy = "depth=1"
+
+            [[source]]
+            url = "github/jsdw"
+            token = { command = "pass show github" }
+            refs = "all"
+
+            [[source]]
+            url = "gitlab/jsdw"
+            name = "gitlab"
+            token = { file = "gitlab-token" }
+            prune = false
+            lfs = false
+            include = ["work-*"]
+            filter = "private"
+            ssh_key = "keys/gitlab"
+            maintenance = false
+        "#).unwrap();
+
+        assert_eq!(config, Config {
+            destination: Some("/backups".into()),
+            token: Some(Token::Env("GIT_TOKEN".to_owned())),
+            prune: Some(true),
+            jobs: Some(4),
+            include: vec![],
+            exclude: vec!["tmp-*".parse().unwrap(), "re:^experiment".parse().unwrap()],
+            filter: Some("!archived && age < 3y".parse().unwrap()),
+            ssh_key: None,
+            known_hosts: Some("known_hosts".into()),
+            refs: Some(RefSelection::BranchesAndTags),
+            lfs: Some(true),
+            submodules: Some(true),
+            timeout: Some(600),
+            retries: Some(3),
+            verify: Some(true),
+            share_objects: Some(true),
+            bundle: Some(true),
+            bundle_dir: Some("/exports".into()),
+            incremental_bundles: Some(true),
+            maintenance: Some(MaintenancePolicy { enabled: true, commit_graph: false, gc_days: 14, ..MaintenancePolicy::default() }),
+            strategies: vec![
+                StrategyRule { filter: "size > 10GB".parse().unwrap(), strategy: CloneStrategy::BlobLimit(1 << 20) },
+                StrategyRule { filter: "name ~ huge-*".parse().unwrap(), strategy: CloneStrategy::Depth(1) },
+            ],
+            sources: vec![
+                Source {
+                    token: Some(Token::Command("pass show github".to_owned())),
+                    refs: Some(RefSelection::All),
+                    ..Source::new("github/jsdw".to_owned())
+                },
+                Source {
+                    name: Some("gitlab".to_owned()),
+                    token: Some(Token::File("gitlab-token".into())),
+                    prune: Some(false),
+                    lfs: Some(false),
+                    include: vec!["work-*".parse().unwrap()],
+                    filter: Some("private".parse().unwrap()),
+                    ssh_key: Some("keys/gitlab".into()),
+                    maintenance: Some(false),
+                    ..Source::new("gitlab/jsdw".to_owned())
+                }
+            ]
+        });
+    }
+
+    #[test]
+    fn test_maintenance_is_not_enabled_by_its_table() {
+        let config = Config::parse("[maintenance]\ngc_days = 14").unwrap();
+        assert_eq!(config.maintenance, Some(MaintenancePolicy { enabled: false, gc_days: 14, ..MaintenancePolicy::default() }));
+        let config = Config::parse("[maintenance]\nenabled = true").unwrap();
+        assert_eq!(config.maintenance, Some(MaintenancePolicy { enabled: true, ..MaintenancePolicy::default() }));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let invalid = vec![
+            // Unknown fields:
+            "foo = 1",
+            "[[source]]\nurl = 'github/jsdw'\nfoo = 1",
+            // Wrong types:
+            "jobs = 'lots'",
+            "prune = 'yes'",
+            "lfs = 'yes'",
+            "exclude = 'foo'",
+            "exclude = ['re:foo(']",
+            "filter = 'size < lots'",
+            "refs = 'pulls'",
+            "[[strategy]]\nfilter = 'private'\nstrategy = 'shallow'",
+            "[[strategy]]\nstrategy = 'depth=1'",
+            "[maintenance]\nprune = true",
+            "[maintenance]\ngc_days = -1",
+            "[maintenance]\nenabled = 'yes'",
+            "[[source]]\nurl = 'github/jsdw'\nmaintenance = { gc_days = 1 }",
+            "[[source]]\nurl = 'github/jsdw'\ninclude = ['foo[']",
+            // Bad values:
+            "jobs = 0",
+            "timeout = 0",
+            "token = { env = '' }",
+            "token = { password = 'abc' }",
+            "[[source]]\nname = 'foo'",
+            "[[source]]\nurl = 'github/jsdw'\nname = '../foo'",
+            "[[source]]\nurl = 'github/jsdw'\n[[source]]\nurl = 'github/jsdw'",
+        ];
+        for contents in invalid {
+            assert!(Config::parse(contents).is_err(), "config should be invalid:\n{}", contents);
+        }
+    }
+
+}
@@ -0,0 +1,628 @@
+use std::fmt;
+use std::str::FromStr;
+use std::convert::TryFrom;
+use chrono::{ DateTime, Duration, NaiveDate, Utc };
+use serde::Deserialize;
+use crate::services::{ Repository, Visibility };
+use super::Pattern;
+
+/// An expression that decides whether a repository should be backed up
+/// based on what the service has told us about it, for example:
+///
+/// ```text
+/// private && !archived && topic == prod && size < 2GB && age < 3y
+/// ```
+///
+/// Comparisons against something that the service didn't tell us
+/// (eg the size of a gist) are always false.
+#[derive(Debug,Clone,PartialEq,Eq,Deserialize)]
+#[serde(try_from = "String")]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Flag(Flag),
+    Compare(Field, Op, Value)
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Flag {
+    Archived,
+    Fork,
+    Public,
+    Internal,
+    Private
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Field {
+    Name,
+    Owner,
+    Description,
+    Language,
+    DefaultBranch,
+    Visibility,
+    Topic,
+    Size,
+    Pushed,
+    Age
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Op {
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    /// Matches a glob or regex pattern
+    Matches
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Value {
+    Text(String),
+    Pattern(Pattern),
+    /// A size in bytes
+    Size(u64),
+    Date(DateTime<Utc>),
+    Duration(Duration)
+}
+
+impl Expr {
+    /// Does the repository given satisfy this expression?
+    pub fn eval(&self, repo: &Repository, now: DateTime<Utc>) -> bool {
+        match self {
+            Expr::And(a, b) => a.eval(repo, now) && b.eval(repo, now),
+            Expr::Or(a, b) => a.eval(repo, now) || b.eval(repo, now),
+            Expr::Not(a) => !a.eval(repo, now),
+            Expr::Flag(flag) => match flag {
+                Flag::Archived => repo.is_archived,
+                Flag::Fork => repo.is_fork,
+                Flag::Public => repo.visibility == Some(Visibility::Public),
+                Flag::Internal => repo.visibility == Some(Visibility::Internal),
+                Flag::Private => repo.visibility == Some(Visibility::Private)
+            },
+            Expr::Compare(field, op, value) => compare(repo, now, *field, *op, value)
+        }
+    }
+    /// The flags that this expression looks at.
+    pub fn flags(&self) -> Vec<Flag> {
+        match self {
+            Expr::And(a, b) | Expr::Or(a, b) => a.flags().into_iter().chain(b.flags()).collect(),
+            Expr::Not(a) => a.flags(),
+            Expr::Flag(flag) => vec![*flag],
+            Expr::Compare(..) => vec![]
+        }
+    }
+    /// If this expression is false for the repository given, find the
+    /// smallest part of it that's responsible, to help explain why.
+    pub fn explain_false(&self, repo: &Repository, now: DateTime<Utc>) -> Option<&Expr> {
+        if self.eval(repo, now) {
+            return None
+        }
+        match self {
+            Expr::And(a, b) => a.explain_false(repo, now).or_else(|| b.explain_false(repo, now)),
+            _ => Some(self)
+        }
+    }
+}
+
+fn compare(repo: &Repository, now: DateTime<Utc>, field: Field, op: Op, value: &Value) -> bool {
+    let text = |s: &Option<String>| s.as_ref().map(|s| compare_text(s, op, value)).unwrap_or(false);
+    match field {
+        Field::Name => compare_text(&repo.name, op, value),
+        Field::Owner => text(&repo.owner),
+        Field::Description => text(&repo.description),
+        Field::Language => text(&repo.language),
+        Field::DefaultBranch => text(&repo.default_branch),
+        Field::Visibility => {
+            let visibility = repo.visibility.map(|v| match v {
+                Visibility::Public => "public",
+                Visibility::Internal => "internal",
+                Visibility::Private => "private"
+            });
+            visibility.map(|v| compare_text(v, op, value)).unwrap_or(false)
+        },
+        Field::Topic => {
+            // A repository has a topic if any of its topics match, and
+            // doesn't have a topic if none of them match:
+            if op == Op::NotEq {
+                !repo.topics.iter().any(|t| compare_text(t, Op::Eq, value))
+            } else {
+                repo.topics.iter().any(|t| compare_text(t, op, value))
+            }
+        },
+        Field::Size => match (repo.size, value) {
+            (Some(size), Value::Size(n)) => compare_ord(size, op, *n),
+            _ => false
+        },
+        Field::Pushed => match (repo.pushed_at, value) {
+            (Some(pushed_at), Value::Date(date)) => compare_ord(pushed_at, op, *date),
+            _ => false
+        },
+        Field::Age => match (repo.pushed_at, value) {
+            (Some(pushed_at), Value::Duration(duration)) => compare_ord(now - pushed_at, op, *duration),
+            _ => false
+        }
+    }
+}
+
+fn compare_text(s: &str, op: Op, value: &Value) -> bool {
+    match (op, value) {
+        (Op::Eq, Value::Text(t)) => s.eq_ignore_ascii_case(t),
+        (Op::NotEq, Value::Text(t)) => !s.eq_ignore_ascii_case(t),
+        (Op::Matches, Value::Pattern(p)) => p.matches(s),
+        _ => false
+    }
+}
+
+fn compare_ord<T: PartialOrd>(a: T, op: Op, b: T) -> bool {
+    match op {
+        Op::Eq => a == b,
+        Op::NotEq => a != b,
+        Op::Lt => a < b,
+        Op::LtEq => a <= b,
+        Op::Gt => a > b,
+        Op::GtEq => a >= b,
+        Op::Matches => false
+    }
+}
+
+impl FromStr for Expr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Expr,String> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.parse_or()?;
+        match parser.next() {
+            None => Ok(expr),
+            Some(token) => Err(format!("Unexpected '{}' in filter", token))
+        }
+    }
+}
+
+impl TryFrom<String> for Expr {
+    type Error = String;
+    fn try_from(s: String) -> Result<Expr,String> {
+        s.parse()
+    }
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+enum Token {
+    LParen,
+    RParen,
+    Not,
+    And,
+    Or,
+    Op(Op),
+    Word(String)
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::LParen => write!(f, "("),
+            Token::RParen => write!(f, ")"),
+            Token::Not => write!(f, "!"),
+            Token::And => write!(f, "&&"),
+            Token::Or => write!(f, "||"),
+            Token::Op(op) => write!(f, "{}", op),
+            Token::Word(w) => write!(f, "{}", w)
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>,String> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue
+        }
+        // Tokens made of two characters:
+        let mut two = |second: char, token: Token, single: Option<Token>| -> Result<(),String> {
+            chars.next();
+            if chars.peek() == Some(&second) {
+                chars.next();
+                tokens.push(token);
+                Ok(())
+            } else if let Some(single) = single {
+                tokens.push(single);
+                Ok(())
+            } else {
+                Err(format!("Expected '{}{}' in filter", c, second))
+            }
+        };
+        match c {
+            '&' => two('&', Token::And, None)?,
+            '|' => two('|', Token::Or, None)?,
+            '=' => two('=', Token::Op(Op::Eq), None)?,
+            '!' => two('=', Token::Op(Op::NotEq), Some(Token::Not))?,
+            '<' => two('=', Token::Op(Op::LtEq), Some(Token::Op(Op::Lt)))?,
+            '>' => two('=', Token::Op(Op::GtEq), Some(Token::Op(Op::Gt)))?,
+            '~' => { chars.next(); tokens.push(Token::Op(Op::Matches)) },
+            '(' => { chars.next(); tokens.push(Token::LParen) },
+            ')' => { chars.next(); tokens.push(Token::RParen) },
+            '"' | '\'' => {
+                chars.next();
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some(ch) => word.push(ch),
+                        None => return Err(format!("Missing closing {} in filter", c))
+                    }
+                }
+                tokens.push(Token::Word(word));
+            },
+            _ => {
+                let mut word = String::new();
+                while let Some(&ch) = chars.peek() {
+                    if ch.is_whitespace() || "&|=!<>~()\"'".contains(ch) {
+                        break
+                    }
+                    word.push(ch);
+                    chars.next();
+                }
+                tokens.push(match &*word.to_lowercase() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    _ => Token::Word(word)
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+    fn parse_or(&mut self) -> Result<Expr,String> {
+        let mut expr = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+    fn parse_and(&mut self) -> Result<Expr,String> {
+        let mut expr = self.parse_not()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_not()?));
+        }
+        Ok(expr)
+    }
+    fn parse_not(&mut self) -> Result<Expr,String> {
+        if self.peek() == Some(&Token::Not) {
+            self.next();
+            Ok(Expr::Not(Box::new(self.parse_not()?)))
+        } else {
+            self.parse_atom()
+        }
+    }
+    fn parse_atom(&mut self) -> Result<Expr,String> {
+        let word = match self.next() {
+            Some(Token::LParen) => {
+                let expr = self.parse_or()?;
+                return match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => Err("Missing closing ')' in filter".to_owned())
+                }
+            },
+            Some(Token::Word(word)) => word,
+            Some(token) => return Err(format!("Unexpected '{}' in filter", token)),
+            None => return Err("Unexpected end of filter".to_owned())
+        };
+
+        // A word on its own is a flag, else it's a comparison:
+        let op = match self.peek() {
+            Some(Token::Op(op)) => *op,
+            _ => return parse_flag(&word).map(Expr::Flag)
+        };
+        self.next();
+        let field = parse_field(&word)?;
+        let value = match self.next() {
+            Some(Token::Word(value)) => value,
+            _ => return Err(format!("Expected a value to compare '{}' with", word))
+        };
+        let value = parse_value(field, op, &value)?;
+        Ok(Expr::Compare(field, op, value))
+    }
+}
+
+fn parse_flag(word: &str) -> Result<Flag,String> {
+    match &*word.to_lowercase() {
+        "archived" => Ok(Flag::Archived),
+        "fork" => Ok(Flag::Fork),
+        "public" => Ok(Flag::Public),
+        "internal" => Ok(Flag::Internal),
+        "private" => Ok(Flag::Private),
+        _ => Err(format!("Unknown flag '{}'; expected one of archived, fork, public, internal or private", word))
+    }
+}
+
+fn parse_field(word: &str) -> Result<Field,String> {
+    match &*word.to_lowercase() {
+        "name" => Ok(Field::Name),
+        "owner" => Ok(Field::Owner),
+        "description" => Ok(Field::Description),
+        "language" => Ok(Field::Language),
+        "default_branch" => Ok(Field::DefaultBranch),
+        "visibility" => Ok(Field::Visibility),
+        "topic" => Ok(Field::Topic),
+        "size" => Ok(Field::Size),
+        "pushed" => Ok(Field::Pushed),
+        "age" => Ok(Field::Age),
+        _ => Err(format!("Unknown field '{}'; expected one of name, owner, description, language, \
+            default_branch, visibility, topic, size, pushed or age", word))
+    }
+}
+
+fn parse_value(field: Field, op: Op, value: &str) -> Result<Value,String> {
+    match field {
+        Field::Size | Field::Pushed | Field::Age if op == Op::Matches => {
+            Err(format!("'~' can't be used with '{}'", field))
+        },
+        Field::Size => parse_size(value).map(Value::Size),
+        Field::Pushed => parse_date(value).map(Value::Date),
+        Field::Age => parse_duration(value).map(Value::Duration),
+        _ => match op {
+            Op::Eq | Op::NotEq => Ok(Value::Text(value.to_owned())),
+            Op::Matches => value.parse().map(Value::Pattern),
+            _ => Err(format!("'{}' can only be compared using ==, != or ~", field))
+        }
+    }
+}
+
+/// Sizes in the largest unit that they can be given exactly in, eg `2GB`.
+pub fn format_size(n: u64) -> String {
+    let unit = ["TB", "GB", "MB", "KB"].iter()
+        .zip(&[1u64 << 40, 1 << 30, 1 << 20, 1 << 10])
+        .find(|(_, &m)| n >= m && n.is_multiple_of(m));
+    match unit {
+        Some((unit, m)) => format!("{}{}", n / m, unit),
+        None => format!("{}B", n)
+    }
+}
+
+/// Sizes like `2GB`, `500MB` or `1024` (bytes).
+pub fn parse_size(s: &str) -> Result<u64,String> {
+    let (n, unit) = split_number(s);
+    let n: f64 = n.parse().map_err(|_| format!("Invalid size '{}'; expected something like 2GB", s))?;
+    let multiplier: u64 = match &*unit.to_lowercase() {
+        "" | "b" => 1,
+        "k" | "kb" => 1024,
+        "m" | "mb" => 1024 * 1024,
+        "g" | "gb" => 1024 * 1024 * 1024,
+        "t" | "tb" => 1024 * 1024 * 1024 * 1024,
+        _ => return Err(format!("Invalid size unit '{}'; expected one of B, KB, MB, GB or TB", unit))
+    };
+    Ok((n * multiplier as f64) as u64)
+}
+
+/// Durations like `3y`, `6mo`, `2w`, `10d` or `12h`.
+fn parse_duration(s: &str) -> Result<Duration,String> {
+    let (n, unit) = split_number(s);
+    let n: i64 = n.parse().map_err(|_| format!("Invalid age '{}'; expected something like 3y", s))?;
+    match &*unit.to_lowercase() {
+        "h" => Ok(Duration::hours(n)),
+        "d" => Ok(Duration::days(n)),
+        "w" => Ok(Duration::weeks(n)),
+        "mo" => Ok(Duration::days(n * 30)),
+        "y" => Ok(Duration::days(n * 365)),
+        _ => Err(format!("Invalid age unit '{}'; expected one of h, d, w, mo or y", unit))
+    }
+}
+
+/// Dates like `2019-10-01` or `2019-10-01T12:00:00Z`.
+fn parse_date(s: &str) -> Result<DateTime<Utc>,String> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(s) {
+        return Ok(date.with_timezone(&Utc))
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+        .map_err(|_| format!("Invalid date '{}'; expected something like 2019-10-01", s))
+}
+
+fn split_number(s: &str) -> (&str, &str) {
+    let idx = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    (&s[..idx], &s[idx..])
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::And(a, b) => write!(f, "({} && {})", a, b),
+            Expr::Or(a, b) => write!(f, "({} || {})", a, b),
+            Expr::Not(a) => write!(f, "!{}", a),
+            Expr::Flag(flag) => write!(f, "{}", match flag {
+                Flag::Archived => "archived",
+                Flag::Fork => "fork",
+                Flag::Public => "public",
+                Flag::Internal => "internal",
+                Flag::Private => "private"
+            }),
+            Expr::Compare(field, op, value) => write!(f, "{} {} {}", field, op, value)
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match self {
+            Field::Name => "name",
+            Field::Owner => "owner",
+            Field::Description => "description",
+            Field::Language => "language",
+            Field::DefaultBranch => "default_branch",
+            Field::Visibility => "visibility",
+            Field::Topic => "topic",
+            Field::Size => "size",
+            Field::Pushed => "pushed",
+            Field::Age => "age"
+        })
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match self {
+            Op::Eq => "==",
+            Op::NotEq => "!=",
+            Op::Lt => "<",
+            Op::LtEq => "<=",
+            Op::Gt => ">",
+            Op::GtEq => ">=",
+            Op::Matches => "~"
+        })
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Text(s) => write!(f, "{:?}", s),
+            Value::Pattern(p) => write!(f, "{:?}", p.as_str()),
+            Value::Size(n) => write!(f, "{}", format_size(*n)),
+            Value::Date(d) => write!(f, "{}", d.to_rfc3339()),
+            Value::Duration(d) => write!(f, "{}d", d.num_days())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn repo() -> Repository {
+        Repository {
+            owner: Some("jsdw".to_owned()),
+            language: Some("Rust".to_owned()),
+            visibility: Some(Visibility::Private),
+            topics: vec!["prod".to_owned(), "backend".to_owned()],
+            size: Some(3 * 1024 * 1024 * 1024),
+            pushed_at: Some(parse_date("2019-06-01").unwrap()),
+            ..Repository::new("my-service".to_owned(), "url".to_owned())
+        }
+    }
+
+    #[test]
+    fn test_eval() {
+        let now = parse_date("2020-01-01").unwrap();
+        let exprs = vec![
+            ("private", true),
+            ("public", false),
+            ("!archived && !fork", true),
+            ("not archived and private", true),
+            ("visibility == private", true),
+            ("visibility != 'public'", true),
+            ("language == rust", true),
+            ("language ~ 'R*'", true),
+            ("name ~ 're:^my-'", true),
+            ("name == other || owner == jsdw", true),
+            ("topic == prod", true),
+            ("topic == staging", false),
+            ("topic != staging", true),
+            ("topic != prod", false),
+            ("topic ~ 'back*'", true),
+            ("size < 2GB", false),
+            ("size > 2GB && size <= 3GB", true),
+            ("size >= 1.5g", true),
+            ("age < 1y", true),
+            ("age < 3mo", false),
+            ("age > 200d", true),
+            ("pushed >= 2019-06-01", true),
+            ("pushed < 2019-01-01T00:00:00Z", false),
+            ("description == foo", false),
+            ("!(description == foo)", true),
+            ("private && (topic == staging || size > 1GB)", true),
+            ("private && topic == staging || size > 1GB", true),
+            ("public && (topic == staging || size > 1GB)", false),
+        ];
+        for (expr, expected) in exprs {
+            let parsed: Expr = expr.parse().unwrap_or_else(|e| panic!("expr {} didn't parse: {}", expr, e));
+            assert_eq!(parsed.eval(&repo(), now), expected, "expr {} should be {}", expr, expected);
+        }
+    }
+
+    #[test]
+    fn test_missing_values() {
+        let now = parse_date("2020-01-01").unwrap();
+        let repo = Repository::new("gist".to_owned(), "url".to_owned());
+        let exprs = vec![
+            ("size < 2GB", false),
+            ("size > 2GB", false),
+            ("!(size > 2GB)", true),
+            ("age < 3y", false),
+            ("private", false),
+            ("language != rust", false),
+            ("topic != prod", true),
+        ];
+        for (expr, expected) in exprs {
+            let parsed: Expr = expr.parse().unwrap();
+            assert_eq!(parsed.eval(&repo, now), expected, "expr {} should be {}", expr, expected);
+        }
+    }
+
+    #[test]
+    fn test_explain_false() {
+        let now = parse_date("2020-01-01").unwrap();
+        let expr: Expr = "private && size < 2GB && !archived".parse().unwrap();
+        assert_eq!(expr.explain_false(&repo(), now).map(|e| e.to_string()), Some("size < 2GB".to_owned()));
+        let expr: Expr = "private".parse().unwrap();
+        assert_eq!(expr.explain_false(&repo(), now), None);
+    }
+
+    #[test]
+    fn test_flags() {
+        let expr: Expr = "(private || !fork) && size < 2GB && !(archived && fork)".parse().unwrap();
+        assert_eq!(expr.flags(), vec![Flag::Private, Flag::Fork, Flag::Archived, Flag::Fork]);
+        let expr: Expr = "name == fork".parse().unwrap();
+        assert_eq!(expr.flags(), vec![]);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let invalid = vec![
+            "",
+            "secret",
+            "colour == red",
+            "size < lots",
+            "size < 2PB",
+            "size ~ 2GB",
+            "age < 3",
+            "pushed > yesterday",
+            "name < foo",
+            "name ~ 'foo['",
+            "private &&",
+            "private & public",
+            "(private",
+            "private)",
+            "name == 'foo",
+            "name ==",
+        ];
+        for expr in invalid {
+            assert!(expr.parse::<Expr>().is_err(), "expr {} should be invalid", expr);
+        }
+    }
+
+}
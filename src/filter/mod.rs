@@ -0,0 +1,210 @@
+mod expr;
+
+use std::str::FromStr;
+use std::convert::TryFrom;
+use regex::Regex;
+use serde::Deserialize;
+use chrono::{ DateTime, Utc };
+use crate::services::Repository;
+
+pub use expr::{ Expr, Flag, parse_size, format_size };
+
+/// Decide which repositories to back up based on their names
+/// and what the service has told us about them.
+#[derive(Debug,Clone,Default)]
+pub struct Filter {
+    /// If not empty, only repositories matching one of these are backed up
+    pub include: Vec<Pattern>,
+    /// Repositories matching any of these are not backed up
+    pub exclude: Vec<Pattern>,
+    /// If provided, only repositories for which this is true are backed up
+    pub expr: Option<Expr>
+}
+
+impl Filter {
+    /// Should the repository given be backed up?
+    #[cfg(test)]
+    pub fn matches(&self, repo: &Repository) -> bool {
+        self.check(repo, Utc::now()).is_ok()
+    }
+    /// Check whether the repository given should be backed up,
+    /// returning the reason why not if it shouldn't be.
+    pub fn check(&self, repo: &Repository, now: DateTime<Utc>) -> Result<(),String> {
+        if !self.include.is_empty() && !self.include.iter().any(|p| p.matches(&repo.name)) {
+            return Err("doesn't match any --include pattern".to_owned())
+        }
+        if let Some(p) = self.exclude.iter().find(|p| p.matches(&repo.name)) {
+            return Err(format!("matches --exclude '{}'", p.as_str()))
+        }
+        if let Some(expr) = &self.expr {
+            if let Some(reason) = expr.explain_false(repo, now) {
+                return Err(format!("filter '{}' is false", reason))
+            }
+        }
+        Ok(())
+    }
+    /// A filter that applies this filter and then the other one.
+    pub fn and(&self, other: &Filter) -> Filter {
+        let expr = match (&self.expr, &other.expr) {
+            (Some(a), Some(b)) => Some(Expr::And(Box::new(a.clone()), Box::new(b.clone()))),
+            (a, b) => a.clone().or_else(|| b.clone())
+        };
+        Filter {
+            include: self.include.iter().chain(&other.include).cloned().collect(),
+            exclude: self.exclude.iter().chain(&other.exclude).cloned().collect(),
+            expr
+        }
+    }
+}
+
+/// A pattern to match repository names against. This is a glob
+/// (eg `foo-*`) unless prefixed with `re:`, in which case it's a
+/// regular expression (eg `re:^foo-[0-9]+$`).
+#[derive(Debug,Clone,Deserialize)]
+#[serde(try_from = "String")]
+pub struct Pattern {
+    source: String,
+    regex: Regex
+}
+
+impl Pattern {
+    pub fn matches(&self, name: &str) -> bool {
+        self.regex.is_match(name)
+    }
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Pattern) -> bool {
+        self.source == other.source
+    }
+}
+impl Eq for Pattern {}
+
+impl FromStr for Pattern {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Pattern,String> {
+        let regex = if let Some(re) = s.strip_prefix("re:") {
+            Regex::new(re).map_err(|e| format!("Invalid regular expression '{}': {}", re, e))?
+        } else {
+            glob_to_regex(s).map_err(|e| format!("Invalid glob pattern '{}': {}", s, e))?
+        };
+        Ok(Pattern { source: s.to_owned(), regex })
+    }
+}
+
+impl TryFrom<String> for Pattern {
+    type Error = String;
+    fn try_from(s: String) -> Result<Pattern,String> {
+        s.parse()
+    }
+}
+
+/// Convert a glob into a regex that matches the whole of a name. We
+/// support `*` (anything), `?` (any single character) and `[...]`
+/// (any of the characters given, or not if the first is `!`).
+fn glob_to_regex(glob: &str) -> Result<Regex,String> {
+    let mut re = String::from("^");
+    let mut chars = glob.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            '[' => {
+                re.push('[');
+                let mut class = String::new();
+                loop {
+                    match chars.next() {
+                        Some(']') if !class.is_empty() => break,
+                        Some(c) => class.push(c),
+                        None => return Err("unclosed '['".to_owned())
+                    }
+                }
+                if let Some(rest) = class.strip_prefix('!') {
+                    re.push('^');
+                    class = rest.to_owned();
+                }
+                for c in class.chars() {
+                    if c == '\\' || c == '[' || c == ']' || c == '^' {
+                        re.push('\\');
+                    }
+                    re.push(c);
+                }
+                re.push(']');
+            },
+            c => re.push_str(&regex::escape(&c.to_string()))
+        }
+    }
+    re.push('$');
+    Regex::new(&re).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_patterns() {
+        let patterns = vec![
+            ("foo", vec!["foo"], vec!["foobar", "xfoo", "Foo"]),
+            ("foo-*", vec!["foo-", "foo-bar", "foo-bar-baz"], vec!["foo", "xfoo-bar"]),
+            ("*.rs", vec!["a.rs", ".rs"], vec!["ars", "a.rsx"]),
+            ("exp-??", vec!["exp-01", "exp-ab"], vec!["exp-1", "exp-001"]),
+            ("v[0-9]", vec!["v1", "v9"], vec!["va", "v10"]),
+            ("v[!0-9]", vec!["va"], vec!["v1"]),
+            ("re:^exp", vec!["exp", "experiment"], vec!["my-exp"]),
+            ("re:[0-9]+$", vec!["foo1", "2"], vec!["foo"]),
+        ];
+        for (pattern, matches, non_matches) in patterns {
+            let p: Pattern = pattern.parse().unwrap();
+            for name in matches {
+                assert!(p.matches(name), "pattern {} should match {}", pattern, name);
+            }
+            for name in non_matches {
+                assert!(!p.matches(name), "pattern {} should not match {}", pattern, name);
+            }
+        }
+
+        assert!("foo[".parse::<Pattern>().is_err());
+        assert!("re:foo(".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let repo = |name: &str| Repository::new(name.to_owned(), "url".to_owned());
+        let filter = Filter {
+            include: vec!["foo-*".parse().unwrap(), "bar".parse().unwrap()],
+            exclude: vec!["*-old".parse().unwrap()],
+            expr: None
+        };
+        assert!(filter.matches(&repo("foo-1")));
+        assert!(filter.matches(&repo("bar")));
+        assert!(!filter.matches(&repo("foo-old")));
+        assert!(!filter.matches(&repo("baz")));
+
+        let exclude_only = Filter {
+            include: vec![],
+            exclude: vec!["re:^tmp".parse().unwrap()],
+            expr: None
+        };
+        assert!(exclude_only.matches(&repo("baz")));
+        assert!(!exclude_only.matches(&repo("tmp-1")));
+
+        let archived = Repository { is_archived: true, ..repo("baz") };
+        let with_expr = exclude_only.and(&Filter {
+            include: vec![],
+            exclude: vec![],
+            expr: Some("!archived".parse().unwrap())
+        });
+        assert!(with_expr.matches(&repo("baz")));
+        assert!(!with_expr.matches(&repo("tmp-1")));
+        assert!(!with_expr.matches(&archived));
+        assert_eq!(with_expr.check(&archived, Utc::now()), Err("filter '!archived' is false".to_owned()));
+
+        assert!(Filter::default().matches(&repo("anything")));
+    }
+
+}
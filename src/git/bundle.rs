@@ -0,0 +1,339 @@
+use std::path::{ Path, PathBuf };
+use serde::{ Serialize, Deserialize };
+use chrono::{ DateTime, Utc };
+use crate::error::Error;
+use super::{ backend, verify_repository, Opts, Refs, Verification };
+use super::command::{ git, git_in, run, run_with_input };
+
+/// The manifest in each chain folder, listing the bundles in the chain.
+const CHAIN_FILE: &str = "chain.json";
+
+/// Where to write the bundles of a backup.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum BundleTarget<'a> {
+    /// A single bundle file, which is written again whenever the refs change
+    File(&'a Path),
+    /// A folder holding a chain of bundles: one with everything in the
+    /// backup to begin with, followed by one with just what's new each time
+    /// the refs change
+    Chain(&'a Path)
+}
+
+#[derive(Debug,Clone,PartialEq,Eq,Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum BundleOutcome {
+    Written {
+        /// Where the bundle was written
+        path: PathBuf,
+        /// How many refs are in the bundle
+        refs: usize,
+        /// The size (in bytes) of the bundle
+        size: u64,
+        /// Does the bundle only have what's new since the last one in its chain?
+        incremental: bool
+    },
+    Failed { error: String }
+}
+
+/// A record of the bundles in a chain, and the refs that the backup had
+/// each time that one was written.
+#[derive(Debug,Clone,Default,PartialEq,Eq,Serialize,Deserialize)]
+struct Chain {
+    links: Vec<Link>
+}
+
+#[derive(Debug,Clone,PartialEq,Eq,Serialize,Deserialize)]
+struct Link {
+    /// The bundle (in the chain's folder) with the objects that were new
+    /// at the time, if there were any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bundle: Option<String>,
+    created: DateTime<Utc>,
+    /// The branch that HEAD pointed to at the time, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    head: Option<String>,
+    /// Every ref in the backup at the time
+    refs: Refs
+}
+
+impl Chain {
+    fn load(folder: &Path) -> Result<Chain,Error> {
+        let path = folder.join(CHAIN_FILE);
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Chain::default()),
+            Err(e) => return Err(err!("Could not read '{}': {}", path.to_string_lossy(), e))
+        };
+        serde_json::from_str(&contents).map_err(|e| err!("Could not parse '{}': {}", path.to_string_lossy(), e))
+    }
+
+    /// The manifest is replaced in one go, so that it's never left half written.
+    fn save(&self, folder: &Path) -> Result<(),Error> {
+        let incomplete = folder.join(format!("{}.incomplete", CHAIN_FILE));
+        std::fs::write(&incomplete, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(&incomplete, folder.join(CHAIN_FILE))?;
+        Ok(())
+    }
+}
+
+/// Write the refs in a backup, and everything that they need, into bundles. Nothing
+/// needs doing if the refs haven't changed since the last bundle was written, or if
+/// the backup doesn't have any.
+pub fn export(opts: &Opts, target: BundleTarget) -> Option<BundleOutcome> {
+    let refs = match backend::refs(opts) {
+        Ok(refs) if refs.is_empty() => return None,
+        Ok(refs) => refs,
+        Err(e) => return Some(BundleOutcome::Failed { error: e.to_string() })
+    };
+    let written = match target {
+        BundleTarget::File(path) => export_file(opts, path, &refs),
+        BundleTarget::Chain(folder) => export_chain(opts, folder, refs)
+    };
+    match written {
+        Ok(outcome) => outcome,
+        Err(e) => Some(BundleOutcome::Failed { error: e.to_string() })
+    }
+}
+
+fn export_file(opts: &Opts, path: &Path, refs: &Refs) -> Result<Option<BundleOutcome>,Error> {
+    if path.is_file() && bundle_refs(opts, path).ok().as_ref() == Some(refs) {
+        return Ok(None)
+    }
+    let size = write_bundle(opts, path, &[])?;
+    Ok(Some(BundleOutcome::Written { path: path.to_owned(), refs: refs.len(), size, incremental: false }))
+}
+
+/// Add a link to the chain of bundles in the folder given, starting a new chain if
+/// there isn't one. Each bundle leaves out everything that could be reached from the
+/// refs recorded for the last link, so long as the backup still has it.
+fn export_chain(opts: &Opts, folder: &Path, refs: Refs) -> Result<Option<BundleOutcome>,Error> {
+    let mut chain = Chain::load(folder)?;
+    let head = head(opts)?;
+    let last = chain.links.last();
+    if last.map(|l| (&l.refs, &l.head)) == Some((&refs, &head)) {
+        return Ok(None)
+    }
+
+    let exclude = match last {
+        Some(last) => existing_objects(opts, last.refs.values())?,
+        None => vec![]
+    };
+    let incremental = last.is_some();
+    let file_name = format!("{:06}.bundle", chain.links.len());
+    let path = folder.join(&file_name);
+
+    // Refs can change without anything new being fetched, eg when they're moved back
+    // to an older commit. There's nothing to bundle then, but the refs are still noted:
+    let outcome = if !incremental || has_new_objects(opts, &exclude)? {
+        let size = write_bundle(opts, &path, &exclude)?;
+        Some(BundleOutcome::Written { path, refs: refs.len(), size, incremental })
+    } else {
+        None
+    };
+    chain.links.push(Link {
+        bundle: outcome.as_ref().map(|_| file_name),
+        created: Utc::now(),
+        head,
+        refs
+    });
+    chain.save(folder)?;
+    Ok(outcome)
+}
+
+/// The branch that HEAD points to, if any.
+fn head(opts: &Opts) -> Result<Option<String>,Error> {
+    let output = git(opts).args(["symbolic-ref", "--quiet", "HEAD"]).output()?;
+    Ok(Some(String::from_utf8_lossy(&output.stdout).trim().to_owned()).filter(|head| output.status.success() && !head.is_empty()))
+}
+
+/// The objects given that the backup still has. Those it doesn't can't be left
+/// out of a bundle, since there'd be nothing to say they can be found elsewhere.
+fn existing_objects<'a>(opts: &Opts, ids: impl Iterator<Item = &'a String>) -> Result<Vec<String>,Error> {
+    let input: String = ids.map(|id| format!("{}\n", id)).collect();
+    let output = run_with_input(git(opts).args(["cat-file", "--batch-check=%(objectname)"]), input)?;
+    Ok(output.lines().filter(|line| !line.ends_with(" missing")).map(|line| line.to_owned()).collect())
+}
+
+/// Is there anything in the backup that can't be reached from the objects given?
+fn has_new_objects(opts: &Opts, exclude: &[String]) -> Result<bool,Error> {
+    let input: String = exclude.iter().map(|id| format!("^{}\n", id)).collect();
+    let output = run_with_input(git(opts).args(["rev-list", "--objects", "--all", "--stdin"]), input)?;
+    Ok(!output.trim().is_empty())
+}
+
+/// Bundles are written alongside where they belong, and only moved into place
+/// once git is happy with them, so that a bundle is never left half written.
+/// Anything that can be reached from the objects given is left out.
+fn write_bundle(opts: &Opts, path: &Path, exclude: &[String]) -> Result<u64,Error> {
+    let parent = path.parent().ok_or_else(|| err!("'{}' is not a valid bundle path", path.to_string_lossy()))?;
+    std::fs::create_dir_all(parent)?;
+    let file_name = path.file_name().ok_or_else(|| err!("'{}' is not a valid bundle path", path.to_string_lossy()))?;
+    let path = parent.canonicalize()?.join(file_name);
+    let mut incomplete = path.clone().into_os_string();
+    incomplete.push(".incomplete");
+    let incomplete = PathBuf::from(incomplete);
+
+    let input: String = exclude.iter().map(|id| format!("^{}\n", id)).collect();
+    let written = run_with_input(git(opts).args(["bundle", "create", "--quiet"]).arg(&incomplete).args(["--all", "--stdin"]), input)
+        .and_then(|_| run(git(opts).args(["bundle", "verify", "--quiet"]).arg(&incomplete)))
+        .and_then(|_| Ok(std::fs::rename(&incomplete, &path)?));
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&incomplete);
+        return Err(e)
+    }
+    Ok(std::fs::metadata(&path)?.len())
+}
+
+/// Put back together the repository that a chain of bundles was written from,
+/// as it was when the last bundle in the chain was written, into a new bare
+/// repository at the destination given. Hands back how many bundles were used.
+pub fn reassemble(folder: &Path, destination: &Path) -> Result<usize,Error> {
+    let chain = Chain::load(folder)?;
+    if chain.links.is_empty() {
+        return Err(err!("'{}' does not contain a chain of bundles", folder.to_string_lossy()))
+    }
+    if destination.exists() && std::fs::read_dir(destination)?.next().is_some() {
+        return Err(err!("Destination '{}' already exists and is not an empty folder", destination.to_string_lossy()))
+    }
+    std::fs::create_dir_all(destination)?;
+    let reassembled = reassemble_into(&chain, &folder.canonicalize()?, destination);
+    if reassembled.is_err() {
+        let _ = std::fs::remove_dir_all(destination);
+    }
+    reassembled?;
+
+    // Everything that the refs need should now be there:
+    if let Verification::Corrupt { error } = verify_repository(destination) {
+        return Err(err!("The reassembled repository is not intact: {}", error))
+    }
+    Ok(chain.links.iter().filter(|l| l.bundle.is_some()).count())
+}
+
+fn reassemble_into(chain: &Chain, folder: &Path, destination: &Path) -> Result<(),Error> {
+    run(git_in(destination).args(["init", "--bare", "--quiet"]))?;
+
+    // Each bundle needs the objects from those before it, which git checks:
+    for bundle in chain.links.iter().filter_map(|l| l.bundle.as_ref()) {
+        let path = folder.join(bundle);
+        run(git_in(destination).args(["bundle", "verify", "--quiet"]).arg(&path))
+            .and_then(|_| run(git_in(destination).args(["bundle", "unbundle"]).arg(&path)))
+            .map_err(|e| err!("Could not apply '{}': {}", path.to_string_lossy(), e))?;
+    }
+
+    // The refs are then set to those the backup had when the last link was added:
+    let last = chain.links.last().expect("chain is not empty");
+    let input: String = last.refs.iter().map(|(name, id)| format!("create {} {}\n", name, id)).collect();
+    run_with_input(git_in(destination).args(["update-ref", "--stdin"]), input)?;
+    if let Some(head) = &last.head {
+        run(git_in(destination).args(["symbolic-ref", "HEAD", head]))?;
+    }
+    Ok(())
+}
+
+/// The refs in a bundle, leaving out HEAD.
+fn bundle_refs(opts: &Opts, path: &Path) -> Result<Refs,Error> {
+    let output = run(git(opts).args(["bundle", "list-heads"]).arg(path.canonicalize()?))?;
+    let refs = output.lines()
+        .filter_map(|line| line.split_once(' '))
+        .filter(|(_, name)| name.starts_with("refs/"))
+        .map(|(id, name)| (name.to_owned(), id.to_owned()))
+        .collect();
+    Ok(refs)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use super::super::test_fixtures::{ run_git, test_opts };
+
+    #[test]
+    fn test_export_file() {
+        let dir = std::env::temp_dir().join(format!("git-backup-test-bundle-{}", std::process::id()));
+        let backup = dir.join("backup.git");
+        let bundle = dir.join("bundles").join("backup.bundle");
+        std::fs::create_dir_all(&backup).unwrap();
+        run_git(&backup, &["init", "--quiet", "--bare"]);
+        let opts = test_opts(&backup);
+
+        // There's nothing to bundle until the backup has some refs:
+        assert_eq!(export(&opts, BundleTarget::File(&bundle)), None);
+        let tree = run_git(&backup, &["write-tree"]);
+        let first = run_git(&backup, &["commit-tree", &tree, "-m", "first"]);
+        run_git(&backup, &["update-ref", "refs/heads/master", &first]);
+
+        let outcome = export(&opts, BundleTarget::File(&bundle)).unwrap();
+        assert!(matches!(outcome, BundleOutcome::Written { refs: 1, .. }), "{:?}", outcome);
+        assert_eq!(run_git(&dir, &["bundle", "list-heads", "bundles/backup.bundle", "refs/heads/master"]),
+            format!("{} refs/heads/master", first));
+
+        // The bundle is only written again once the refs change:
+        assert_eq!(export(&opts, BundleTarget::File(&bundle)), None);
+        let second = run_git(&backup, &["commit-tree", &tree, "-p", &first, "-m", "second"]);
+        run_git(&backup, &["update-ref", "refs/tags/v1", &second]);
+        let outcome = export(&opts, BundleTarget::File(&bundle)).unwrap();
+        assert!(matches!(outcome, BundleOutcome::Written { refs: 2, .. }), "{:?}", outcome);
+
+        // Which can be cloned from, just like the backup itself:
+        run_git(&dir, &["clone", "--quiet", "--bare", "--mirror", "bundles/backup.bundle", "restored.git"]);
+        assert_eq!(run_git(&dir.join("restored.git"), &["rev-parse", "v1"]), second);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_export_chain() {
+        let dir = std::env::temp_dir().join(format!("git-backup-test-bundle-chain-{}", std::process::id()));
+        let work = dir.join("work");
+        let backup = dir.join("backup.git");
+        let chain = dir.join("backup.bundles");
+        std::fs::create_dir_all(&work).unwrap();
+        std::fs::create_dir_all(&backup).unwrap();
+        run_git(&work, &["init", "--quiet"]);
+        run_git(&backup, &["init", "--quiet", "--bare"]);
+        run_git(&backup, &["symbolic-ref", "HEAD", "refs/heads/main"]);
+        let opts = test_opts(&backup);
+        let commit = |file: &str, contents: Vec<u8>| {
+            std::fs::write(work.join(file), contents).unwrap();
+            run_git(&work, &["add", file]);
+            run_git(&work, &["commit", "--quiet", "-m", file]);
+            run_git(&backup, &["fetch", "--quiet", "../work", "+HEAD:refs/heads/main"]);
+            run_git(&backup, &["rev-parse", "main"])
+        };
+        let written = |incremental: bool| match export(&opts, BundleTarget::Chain(&chain)) {
+            Some(BundleOutcome::Written { path, incremental: i, .. }) if i == incremental => std::fs::metadata(path).unwrap().len(),
+            outcome => panic!("expected a bundle to be written, but got {:?}", outcome)
+        };
+
+        // The first bundle has everything in the backup:
+        let noise: Vec<u8> = (0..100_000).scan(0x2545f491u32, |x, _| {
+            *x ^= *x << 13;
+            *x ^= *x >> 17;
+            *x ^= *x << 5;
+            Some(*x as u8)
+        }).collect();
+        let first = commit("big", noise);
+        assert!(written(false) > 50_000);
+        assert_eq!(export(&opts, BundleTarget::Chain(&chain)), None);
+
+        // Those after it only have what's new:
+        let second = commit("small", b"small".to_vec());
+        assert!(written(true) < 10_000);
+
+        // Refs that change without anything new are just noted down:
+        run_git(&backup, &["update-ref", "refs/heads/main", &first]);
+        assert_eq!(export(&opts, BundleTarget::Chain(&chain)), None);
+        assert_eq!(Chain::load(&chain).unwrap().links.len(), 3);
+
+        // The backup can then be put back together as it was last time:
+        let restored = dir.join("restored.git");
+        assert_eq!(reassemble(&chain, &restored).unwrap(), 2);
+        assert_eq!(run_git(&restored, &["rev-parse", "main"]), first);
+        assert_eq!(run_git(&restored, &["symbolic-ref", "HEAD"]), "refs/heads/main");
+        assert_eq!(run_git(&restored, &["cat-file", "-t", &second]), "commit");
+        assert!(reassemble(&chain, &restored).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
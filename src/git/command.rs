@@ -0,0 +1,561 @@
+use std::io::{ Read, Write };
+use std::process::{ Command, Child, Stdio, Output };
+use std::time::{ Duration, Instant };
+use std::path::Path;
+use std::collections::{ BTreeSet, HashSet };
+use std::sync::mpsc::{ self, Sender, RecvTimeoutError };
+use regex::Regex;
+use lazy_static::lazy_static;
+use crate::error::Error;
+use super::{ Opts, SshOpts, Refs, RemoteRefs, CloneStrategy, OLD_CREDENTIAL_HELPER_RE, credential_target };
+
+/// Hands git the username and password from the environment when asked for
+/// them, but only for the protocol and host of the remote that we're syncing.
+/// This is given on the command line each time that git is run, so it's
+/// never saved anywhere.
+const CREDENTIAL_HELPER: &str = concat!(
+    r#"credential.helper=!f() { test "$1" = get || return 0; "#,
+    r#"while IFS== read -r key value; do case "$key" in protocol) protocol="$value";; host) host="$value";; esac; done; "#,
+    r#"test -n "$GIT_BACKUP_HOST" && test "$protocol" = "$GIT_BACKUP_PROTOCOL" && test "$host" = "$GIT_BACKUP_HOST" || return 0; "#,
+    r#"echo "username=${GIT_USER}"; echo "password=${GIT_PASSWORD}"; }; f"#
+);
+
+pub fn init(opts: &Opts) -> Result<(),Error> {
+    run(git(opts).args(["init", "--bare", "--quiet"]))?;
+    Ok(())
+}
+
+pub fn configure(opts: &Opts) -> Result<(),Error> {
+    remove_old_credential_helper(opts)?;
+    run(git(opts).args(["config", "remote.origin.url", opts.repo_url]))?;
+    Ok(())
+}
+
+pub fn ls_remote(opts: &Opts) -> Result<RemoteRefs,Error> {
+    let output = run_with_timeout(git(opts).args(["ls-remote", "--symref", "origin"]), opts.timeout)?;
+    Ok(parse_ls_remote(&output))
+}
+
+pub fn fetch(opts: &Opts, refspecs: &[String], strategy: CloneStrategy) -> Result<(),Error> {
+    // Don't let git clean up after fetching; anything that's no longer
+    // referenced may be history that we want to keep. Tags are covered
+    // by the refspecs, if we want them:
+    let mut cmd = git(opts);
+    cmd.args(["-c", "gc.auto=0", "-c", "maintenance.auto=false", "fetch", "--prune", "--no-tags", "--progress"]);
+    match strategy {
+        CloneStrategy::BlobLimit(size) => { cmd.arg(format!("--filter=blob:limit={}", size)); },
+        CloneStrategy::Depth(depth) => { cmd.arg(format!("--depth={}", depth)); },
+        CloneStrategy::Full | CloneStrategy::SingleBranch => {}
+    }
+    run_with_timeout(cmd.arg("origin").args(refspecs), opts.timeout)?;
+    Ok(())
+}
+
+pub fn get_config(opts: &Opts, key: &str) -> Result<Option<String>,Error> {
+    let output = git(opts).args(["config", "--local", "--get", key]).output()?;
+    // An exit code of 1 means that the key isn't set:
+    match output.status.code() {
+        Some(0) => Ok(Some(String::from_utf8_lossy(&output.stdout).trim().to_owned())),
+        Some(1) => Ok(None),
+        _ => Err(err!("{}", String::from_utf8_lossy(&output.stderr).trim()))
+    }
+}
+
+pub fn set_config(opts: &Opts, key: &str, value: &str) -> Result<(),Error> {
+    run(git(opts).args(["config", "--local", key, value]))?;
+    Ok(())
+}
+
+pub fn set_head(opts: &Opts, branch: &str) -> Result<(),Error> {
+    run(git(opts).args(["symbolic-ref", "HEAD", &format!("refs/heads/{}", branch)]))?;
+    Ok(())
+}
+
+pub fn refs(opts: &Opts) -> Result<Refs,Error> {
+    let output = run(git(opts).args(["for-each-ref", "--format=%(objectname) %(refname) %(symref)"]))?;
+    let refs = output.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        // Symbolic refs have a third field naming the ref that they point to:
+        .filter(|fields| fields.len() == 2)
+        .map(|fields| (fields[1].to_owned(), fields[0].to_owned()))
+        .collect();
+    Ok(refs)
+}
+
+/// The contents of the `.gitmodules` file in each ref, leaving out duplicates.
+pub fn gitmodules(opts: &Opts) -> Result<Vec<String>,Error> {
+    let commits: BTreeSet<String> = refs(opts)?.into_values().collect();
+
+    // Ask for every file at once, rather than running git for each ref:
+    let input: String = commits.iter().map(|id| format!("{}:.gitmodules\n", id)).collect();
+    let output = run_with_input_bytes(git(opts).args(["cat-file", "--batch"]), input)?;
+
+    let mut seen = HashSet::new();
+    let files = parse_cat_file_batch(&output)
+        .into_iter()
+        .filter(|(id, _)| seen.insert(id.to_owned()))
+        .map(|(_, contents)| String::from_utf8_lossy(contents).into_owned())
+        .collect();
+    Ok(files)
+}
+
+pub fn is_ancestor(opts: &Opts, ancestor: &str, descendant: &str) -> bool {
+    git(opts)
+        .args(["merge-base", "--is-ancestor", ancestor, descendant])
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+pub fn set_ref(opts: &Opts, name: &str, id: &str) -> Result<(),Error> {
+    run(git(opts).args(["update-ref", name, id]))?;
+    Ok(())
+}
+
+pub fn delete_ref(opts: &Opts, name: &str) -> Result<(),Error> {
+    run(git(opts).args(["update-ref", "-d", name]))?;
+    Ok(())
+}
+
+pub fn has_object(opts: &Opts, id: &str) -> bool {
+    git(opts)
+        .args(["cat-file", "-e", id])
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+/// Fetch an object from another repository on disk, along with everything it
+/// refers to, without creating any refs of our own. The object needn't have a
+/// ref pointing to it, which git only allows when asked for over protocol v2.
+pub fn fetch_object_from(opts: &Opts, path: &Path, id: &str) -> Result<(),Error> {
+    run(git(opts)
+        .args(["-c", "gc.auto=0", "-c", "maintenance.auto=false", "-c", "protocol.version=2", "fetch", "--no-tags"])
+        .arg(path)
+        .arg(id))?;
+    Ok(())
+}
+
+/// Check that every object in the repository is intact, and that everything
+/// reachable from a ref is there. Objects that nothing refers to are fine.
+pub fn fsck(path: &Path) -> Result<(),Error> {
+    run(Command::new("git").args(["fsck", "--no-dangling", "--no-progress"]).current_dir(path))?;
+    Ok(())
+}
+
+/// Each line of `git ls-remote --symref` output is either `<id><tab><ref>`,
+/// or `ref: <target><tab><ref>` for symbolic refs like HEAD.
+fn parse_ls_remote(output: &str) -> RemoteRefs {
+    let mut remote = RemoteRefs { head: None, refs: Refs::new() };
+    for line in output.lines() {
+        let mut fields = line.splitn(2, '\t');
+        let (target, name) = match (fields.next(), fields.next()) {
+            (Some(target), Some(name)) => (target, name),
+            _ => continue
+        };
+        if let Some(target) = target.strip_prefix("ref: ") {
+            if name == "HEAD" {
+                remote.head = target.strip_prefix("refs/heads/").map(|b| b.to_owned());
+            }
+        } else if name.starts_with("refs/") && !name.ends_with("^{}") {
+            // Annotated tags are also listed with '^{}' on the end,
+            // alongside the commit that they point to.
+            remote.refs.insert(name.to_owned(), target.to_owned());
+        }
+    }
+    remote
+}
+
+/// `git cat-file --batch` outputs `<id> <type> <size>` followed by the object
+/// on the next line, or `<name> missing` for each object that it can't find.
+fn parse_cat_file_batch(output: &[u8]) -> Vec<(String, &[u8])> {
+    let mut objects = vec![];
+    let mut rest = output;
+    while let Some(end) = rest.iter().position(|&b| b == b'\n') {
+        let header = String::from_utf8_lossy(&rest[..end]).into_owned();
+        rest = &rest[end + 1..];
+        let fields: Vec<&str> = header.split(' ').collect();
+        let size = match fields.as_slice() {
+            [_, _, size] => match size.parse::<usize>() {
+                Ok(size) if size <= rest.len() => size,
+                _ => break
+            },
+            _ => continue
+        };
+        objects.push((fields[0].to_owned(), &rest[..size]));
+        // Each object is followed by a newline:
+        rest = rest.get(size + 1..).unwrap_or(&[]);
+    }
+    objects
+}
+
+/// Remove the credential helper that older versions of this tool saved
+/// into the config of each backup, if it's there.
+fn remove_old_credential_helper(opts: &Opts) -> Result<(),Error> {
+    let output = git(opts)
+        .args(["config", "--local", "--unset-all", "credential.helper", OLD_CREDENTIAL_HELPER_RE])
+        .output()?;
+    // An exit code of 5 means that there was nothing to remove:
+    match output.status.code() {
+        Some(0) | Some(5) => Ok(()),
+        _ => Err(err!("{}", String::from_utf8_lossy(&output.stderr).trim()))
+    }
+}
+
+/// Prepare to run git in the destination folder. Arguments are handed straight
+/// to git rather than via a shell, so nothing in them is interpreted.
+pub(super) fn git(opts: &Opts) -> Command {
+    let mut cmd = Command::new("git");
+    cmd
+        // Clear any credential helpers that have been configured, so that
+        // they aren't asked for (or told) our credentials, and use ours:
+        .args(["-c", "credential.helper=", "-c", CREDENTIAL_HELPER])
+        // Fail rather than wait for input if our credentials aren't accepted:
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .env("GIT_USER", opts.username)
+        .env("GIT_PASSWORD", opts.password)
+        .current_dir(opts.destination);
+    if let Some((protocol, host)) = credential_target(opts.repo_url) {
+        cmd.env("GIT_BACKUP_PROTOCOL", protocol).env("GIT_BACKUP_HOST", host);
+    }
+    if let Some(ssh) = opts.ssh {
+        cmd.env("GIT_SSH_COMMAND", ssh_command(ssh));
+    }
+    // Have curl give up on HTTP transfers that stall for as long as the timeout:
+    if let Some(timeout) = opts.timeout {
+        cmd.args(["-c", "http.lowSpeedLimit=1", "-c", &format!("http.lowSpeedTime={}", timeout.as_secs())]);
+    }
+    cmd
+}
+
+/// Run git in a repository that doesn't belong to a remote, like an object pool.
+pub(super) fn git_in(path: &Path) -> Command {
+    let mut cmd = Command::new("git");
+    cmd.current_dir(path);
+    cmd
+}
+
+/// The command that git should use to connect over SSH. We only use the key
+/// given, never prompt for anything, refuse to connect to unknown hosts, and
+/// give up on connections that stop responding for a minute.
+fn ssh_command(ssh: &SshOpts) -> String {
+    let mut cmd = format!("ssh -i {} -o IdentitiesOnly=yes -o BatchMode=yes -o StrictHostKeyChecking=yes -o ServerAliveInterval=15 -o ServerAliveCountMax=4", shell_quote(&ssh.key));
+    if let Some(known_hosts) = &ssh.known_hosts {
+        cmd.push_str(&format!(" -o UserKnownHostsFile={}", shell_quote(known_hosts)));
+    }
+    cmd
+}
+
+/// git runs GIT_SSH_COMMAND using a shell, so paths in it need quoting.
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', r"'\''"))
+}
+
+/// Run a git command, handing back its output if it succeeds.
+pub(super) fn run(cmd: &mut Command) -> Result<String,Error> {
+    check_output(cmd.output()?)
+}
+
+/// Run a git command like [`run`], handing it the input given. We write on
+/// another thread so that neither of us waits on the other to read.
+pub(super) fn run_with_input(cmd: &mut Command, input: String) -> Result<String,Error> {
+    let stdout = run_with_input_bytes(cmd, input)?;
+    Ok(String::from_utf8_lossy(&stdout).into_owned())
+}
+
+/// Run a git command like [`run_with_input`], handing back exactly what it printed.
+fn run_with_input_bytes(cmd: &mut Command, input: String) -> Result<Vec<u8>,Error> {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output()?;
+    writer.join().map_err(|_| err!("Could not write to git"))??;
+    check_status(output)
+}
+
+/// Run a git command like [`run`], but kill it (and anything that it started,
+/// like ssh) if it goes longer than the timeout given (if any) without any
+/// output, so that a connection that hangs can't block us forever. Commands
+/// that take a while should be asked to report their progress, so that a large
+/// transfer that's still going isn't mistaken for one that's stalled.
+pub(super) fn run_with_timeout(cmd: &mut Command, timeout: Option<Duration>) -> Result<String,Error> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return run(cmd)
+    };
+    let mut child = in_process_group(cmd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let (sender, receiver) = mpsc::channel();
+    read_in_background(child.stdout.take(), sender.clone(), false);
+    read_in_background(child.stderr.take(), sender, true);
+
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+    let mut status = None;
+    let mut last_output = Instant::now();
+    loop {
+        match receiver.recv_timeout(Duration::from_millis(100)) {
+            Ok((is_stderr, bytes)) => {
+                if is_stderr { stderr.extend(bytes) } else { stdout.extend(bytes) }
+                last_output = Instant::now();
+            },
+            // Everything holding on to the output has finished with it:
+            Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+        if status.is_none() {
+            status = child.try_wait()?;
+        }
+        if last_output.elapsed() >= timeout {
+            // Don't wait for the readers; once everything is killed, they'll
+            // finish, and if something escaped, they'll be left to it:
+            kill_process_group(&mut child);
+            match status {
+                // git finished, but left something running that kept the output open:
+                Some(_) => break,
+                None => return Err(err!("Timed out after {} seconds without any progress", timeout.as_secs()))
+            }
+        }
+    }
+    let status = match status {
+        Some(status) => status,
+        None => child.wait()?
+    };
+    let stderr = without_progress(&String::from_utf8_lossy(&stderr)).into_bytes();
+    check_output(Output { status, stdout, stderr })
+}
+
+/// Start a command in its own process group, so that anything it starts can be killed along with it.
+#[cfg(unix)]
+fn in_process_group(cmd: &mut Command) -> &mut Command {
+    use std::os::unix::process::CommandExt;
+    cmd.process_group(0)
+}
+
+#[cfg(not(unix))]
+fn in_process_group(cmd: &mut Command) -> &mut Command {
+    cmd
+}
+
+/// Kill a command started with [`in_process_group`], and everything in its group.
+#[cfg(unix)]
+fn kill_process_group(child: &mut Child) {
+    // The group has the same ID as the process that leads it:
+    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL); }
+    let _ = child.wait();
+}
+
+#[cfg(not(unix))]
+fn kill_process_group(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Read everything from a pipe on another thread as it arrives, so that the
+/// process writing to it never waits for us, and we know that it's still going.
+fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>, sender: Sender<(bool,Vec<u8>)>, is_stderr: bool) {
+    let mut pipe = match pipe {
+        Some(pipe) => pipe,
+        None => return
+    };
+    std::thread::spawn(move || {
+        let mut buf = [0; 8192];
+        loop {
+            match pipe.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => if sender.send((is_stderr, buf[..n].to_vec())).is_err() { break }
+            }
+        }
+    });
+}
+
+/// Progress reports are redrawn using carriage returns, and aren't worth
+/// keeping once a command is done, so drop them from what it printed.
+fn without_progress(stderr: &str) -> String {
+    lazy_static! {
+        static ref PROGRESS_RE: Regex = Regex::new(r"^(?:remote: )?(?:(?:Enumerating|Counting|Compressing|Receiving|Resolving|Unpacking|Checking|Updating|Filtering|Finding|Downloading) [a-zA-Z ]*:|Total \d)").unwrap();
+    }
+    stderr.lines()
+        .map(|line| line.rsplit('\r').next().unwrap_or(""))
+        .filter(|line| !line.trim().is_empty() && !PROGRESS_RE.is_match(line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Hand back the output of a git command if it succeeded, or say why it didn't.
+fn check_output(output: Output) -> Result<String,Error> {
+    let stdout = check_status(output)?;
+    Ok(String::from_utf8_lossy(&stdout).into_owned())
+}
+
+/// Like [`check_output`], but hand back exactly what the command printed.
+fn check_status(output: Output) -> Result<Vec<u8>,Error> {
+    if output.status.success() {
+        return Ok(output.stdout)
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.contains("Host key verification failed") {
+        Err(err!("Host key verification failed; the host's key is not in the known hosts file, or has changed:\n{}", stderr.trim()))
+    } else {
+        Err(err!("{}", stderr.trim()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use super::super::test_fixtures::test_opts;
+
+    #[test]
+    fn test_urls_are_not_interpreted() {
+        let dir = std::env::temp_dir().join(format!("git-backup-test-{}", std::process::id()));
+        let marker = dir.join("marker");
+        let destination = dir.join("repo");
+        std::fs::create_dir_all(&destination).unwrap();
+
+        // These are valid file URLs, so they'll be handed to git:
+        let urls = vec![
+            format!("file:///nonexistent/$(touch {})", marker.to_string_lossy()),
+            format!("file:///nonexistent/`touch {}`", marker.to_string_lossy()),
+            format!("file:///nonexistent/';touch${{IFS}}{};'", marker.to_string_lossy()),
+        ];
+        for url in &urls {
+            let opts = Opts { repo_url: url, ..test_opts(&destination) };
+            assert!(init(&opts).and_then(|_| configure(&opts)).and_then(|_| ls_remote(&opts)).is_err(), "url {} should not have been fetched from", url);
+            assert!(!marker.exists(), "url {} was interpreted by a shell", url);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_credentials_are_only_given_to_the_remote() {
+        let destination = std::env::temp_dir().join(format!("git-backup-test-credentials-{}", std::process::id()));
+        std::fs::create_dir_all(&destination).unwrap();
+        let opts = Opts { repo_url: "https://github.com/jsdw/git-backup.git", ..test_opts(&destination) };
+        let fill = |request: &str| run_with_input(git(&opts).args(["credential", "fill"]), request.to_owned());
+
+        assert_eq!(fill("protocol=https\nhost=github.com\n\n").unwrap(), "protocol=https\nhost=github.com\nusername=user\npassword=password\n");
+        let others = vec![
+            "protocol=https\nhost=evil.com\n\n",
+            "protocol=http\nhost=github.com\n\n",
+            "protocol=https\nhost=github.com:8443\n\n",
+            "protocol=https\nhost=github.com.evil.com\n\n",
+        ];
+        for request in others {
+            assert!(fill(request).is_err(), "credentials were given for {:?}", request);
+        }
+
+        std::fs::remove_dir_all(&destination).unwrap();
+    }
+
+    #[test]
+    fn test_remove_old_credential_helper() {
+        let destination = std::env::temp_dir().join(format!("git-backup-test-helper-{}", std::process::id()));
+        std::fs::create_dir_all(&destination).unwrap();
+        let opts = Opts { repo_url: "file:///nonexistent", ..test_opts(&destination) };
+        let old_helper = r#"!f() { sleep 1; echo "username=${GIT_USER}"; echo "password=${GIT_PASSWORD}"; }; f"#;
+        let helpers = || {
+            let output = Command::new("git")
+                .args(["config", "--local", "--get-all", "credential.helper"])
+                .current_dir(&destination)
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        };
+
+        run(Command::new("git").args(["init", "--bare", "--quiet"]).current_dir(&destination)).unwrap();
+        run(Command::new("git").args(["config", "--add", "credential.helper", old_helper]).current_dir(&destination)).unwrap();
+        run(Command::new("git").args(["config", "--add", "credential.helper", "cache"]).current_dir(&destination)).unwrap();
+
+        // The old helper is removed, but anything else is left alone:
+        remove_old_credential_helper(&opts).unwrap();
+        assert_eq!(helpers(), "cache\n");
+        // Nothing to remove isn't an error:
+        remove_old_credential_helper(&opts).unwrap();
+        assert_eq!(helpers(), "cache\n");
+
+        std::fs::remove_dir_all(&destination).unwrap();
+    }
+
+    #[test]
+    fn test_parse_ls_remote() {
+        let output = "ref: refs/heads/main\tHEAD\n\
+                      b65ac6b76c8834d06a3845610517c5e3b3fce34a\tHEAD\n\
+                      b65ac6b76c8834d06a3845610517c5e3b3fce34a\trefs/heads/main\n\
+                      1d1e8dbb5a1c26cd4b3e4d3e2dbd3bf6e25e5a7f\trefs/pull/1/head\n\
+                      5f5b3c1c1b64a3e8bd0f2aef0c6d3c4b0bb8f9a1\trefs/tags/v1\n\
+                      b65ac6b76c8834d06a3845610517c5e3b3fce34a\trefs/tags/v1^{}\n";
+        let remote = parse_ls_remote(output);
+        assert_eq!(remote.head.as_deref(), Some("main"));
+        let names: Vec<&str> = remote.refs.keys().map(|k| &**k).collect();
+        assert_eq!(names, vec!["refs/heads/main", "refs/pull/1/head", "refs/tags/v1"]);
+        assert_eq!(remote.refs["refs/tags/v1"], "5f5b3c1c1b64a3e8bd0f2aef0c6d3c4b0bb8f9a1");
+
+        // Empty repositories have no HEAD:
+        let remote = parse_ls_remote("");
+        assert_eq!(remote.head, None);
+        assert!(remote.refs.is_empty());
+    }
+
+    #[test]
+    fn test_parse_cat_file_batch() {
+        let output = b"abc123 blob 11\n[submodule]\n0123 missing\ndef456 blob 0\n\n";
+        let objects = parse_cat_file_batch(output);
+        assert_eq!(objects, vec![
+            ("abc123".to_owned(), &b"[submodule]"[..]),
+            ("def456".to_owned(), &b""[..]),
+        ]);
+    }
+
+    #[test]
+    fn test_run_with_timeout() {
+        let dir = std::env::temp_dir().join(format!("git-backup-test-timeout-{}", std::process::id()));
+        let marker = dir.join("marker");
+        std::fs::create_dir_all(&dir).unwrap();
+        let timeout = Some(Duration::from_millis(500));
+
+        // Commands that keep reporting progress can take as long as they need:
+        let output = run_with_timeout(Command::new("sh").args(["-c", "for i in 1 2 3 4; do echo $i >&2; sleep 0.3; done; echo done"]), timeout);
+        assert_eq!(output.unwrap(), "done\n");
+
+        // Those that stall are killed, along with anything they started:
+        let script = format!("(sleep 1; touch '{}') & wait", marker.to_string_lossy());
+        let started = Instant::now();
+        assert!(run_with_timeout(Command::new("sh").args(["-c", &script]), timeout).is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+        std::thread::sleep(Duration::from_millis(1500));
+        assert!(!marker.exists(), "the command's children were left running");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_without_progress() {
+        let stderr = "remote: Enumerating objects: 5, done.\nremote: Counting objects:  50% (1/2)\rremote: Counting objects: 100% (2/2), done.\n\
+            remote: Total 5 (delta 0), reused 0 (delta 0)\nReceiving objects:  40% (2/5)\rReceiving objects: 100% (5/5), done.\n\
+            error: RPC failed; curl 56 Recv failure\nfatal: early EOF\n";
+        assert_eq!(without_progress(stderr), "error: RPC failed; curl 56 Recv failure\nfatal: early EOF");
+    }
+
+    #[test]
+    fn test_ssh_command() {
+        let ssh = SshOpts {
+            key: "/keys/it's a key".into(),
+            known_hosts: Some("/keys/known hosts".into())
+        };
+        assert_eq!(
+            ssh_command(&ssh),
+            r"ssh -i '/keys/it'\''s a key' -o IdentitiesOnly=yes -o BatchMode=yes -o StrictHostKeyChecking=yes -o ServerAliveInterval=15 -o ServerAliveCountMax=4 -o UserKnownHostsFile='/keys/known hosts'"
+        );
+    }
+
+}
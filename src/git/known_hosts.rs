@@ -0,0 +1,167 @@
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+/// What a known_hosts file has to say about the key that a host gave us.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum HostKeyStatus {
+    /// The key is listed for the host
+    Known,
+    /// A different key of the same type is listed for the host,
+    /// or the key has been revoked
+    Changed,
+    /// No key of this type is listed for the host
+    Unknown
+}
+
+/// Check a host's key (the raw key blob, as found base64 encoded in
+/// known_hosts files) against the contents of a known_hosts file. Hashed
+/// host names, wildcards, negated patterns and revoked keys are understood.
+pub fn check(contents: &str, host: &str, port: u16, key: &[u8]) -> HostKeyStatus {
+    let name = if port == 22 {
+        host.to_lowercase()
+    } else {
+        format!("[{}]:{}", host.to_lowercase(), port)
+    };
+
+    let mut status = HostKeyStatus::Unknown;
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue
+        }
+
+        let mut fields = line.split_whitespace();
+        let mut hosts = fields.next().unwrap_or("");
+        let mut revoked = false;
+        if hosts.starts_with('@') {
+            match hosts {
+                "@revoked" => revoked = true,
+                // Certificate authorities aren't supported:
+                _ => continue
+            }
+            hosts = fields.next().unwrap_or("");
+        }
+        let line_key = match fields.nth(1).and_then(|k| base64::decode(k).ok()) {
+            Some(line_key) => line_key,
+            None => continue
+        };
+
+        if !hosts_match(hosts, &name) {
+            continue
+        }
+        if line_key == key {
+            if revoked {
+                return HostKeyStatus::Changed
+            }
+            status = HostKeyStatus::Known;
+        } else if !revoked && status == HostKeyStatus::Unknown && key_type(&line_key) == key_type(key) {
+            status = HostKeyStatus::Changed;
+        }
+    }
+    status
+}
+
+/// Does a comma separated list of host patterns from a known_hosts file match the name given?
+fn hosts_match(patterns: &str, name: &str) -> bool {
+    let mut is_match = false;
+    for pattern in patterns.split(',') {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern)
+        };
+        let matches = match pattern.strip_prefix("|1|") {
+            Some(hashed) => hashed_host_matches(hashed, name),
+            None => wildcard_matches(pattern.to_lowercase().as_bytes(), name.as_bytes())
+        };
+        if matches && negated {
+            return false
+        }
+        is_match = is_match || matches;
+    }
+    is_match
+}
+
+/// Hashed host names look like `|1|<salt>|<hash>`, where the hash is an
+/// HMAC-SHA1 of the host name keyed with the salt.
+fn hashed_host_matches(hashed: &str, name: &str) -> bool {
+    let mut parts = hashed.splitn(2, '|');
+    let salt = parts.next().and_then(|s| base64::decode(s).ok());
+    let hash = parts.next().and_then(|s| base64::decode(s).ok());
+    match (salt, hash) {
+        (Some(salt), Some(hash)) => hmac_sha1(&salt, name.as_bytes()).map(|h| h == hash).unwrap_or(false),
+        _ => false
+    }
+}
+
+fn hmac_sha1(key: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    let pkey = PKey::hmac(key).ok()?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &pkey).ok()?;
+    signer.update(data).ok()?;
+    signer.sign_to_vec().ok()
+}
+
+/// Match a pattern where '*' matches any number of characters and '?' matches one.
+fn wildcard_matches(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => wildcard_matches(&pattern[1..], name) || (!name.is_empty() && wildcard_matches(pattern, &name[1..])),
+        (Some(b'?'), Some(_)) => wildcard_matches(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => wildcard_matches(&pattern[1..], &name[1..]),
+        _ => false
+    }
+}
+
+/// Key blobs begin with their type (eg `ssh-ed25519`), prefixed with its length.
+fn key_type(key: &[u8]) -> Option<&[u8]> {
+    if key.len() < 4 {
+        return None
+    }
+    let len = u32::from_be_bytes([key[0], key[1], key[2], key[3]]) as usize;
+    key.get(4..4 + len)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    const KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIAg1by6bnuQ+OPEP0lwwKFZpcZlsZm2+auuZXgYrD2JM";
+    const OTHER_KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIP0jdfHGChax975jPuET+VL/q9TbkWYi7aDS6w74KyoY";
+
+    #[test]
+    fn test_check() {
+        let key = base64::decode(KEY).unwrap();
+        let plain = format!("# A comment\ngitlab.com,example.com ssh-ed25519 {}\n*.example.org ssh-ed25519 {}\n", KEY, KEY);
+        // Generated with 'ssh-keygen -H' for 'example.com' and '[example.com]:2222':
+        let hashed = format!(
+            "|1|pdQ3upC9Is282PSEP+X+qqSEUHw=|Mx0/Bcqm/u95dp6Hz9//UayAkVA= ssh-ed25519 {}\n\
+             |1|eQcuuj6h1cWIc3krPRJxzylQtzQ=|WHCTrjHgD8YoCDTwI+wLcxhAyhc= ssh-ed25519 {}\n",
+            KEY, KEY);
+        let changed = format!("example.com ssh-ed25519 {}\n", OTHER_KEY);
+        let other_type = "example.com ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQ==\n";
+        let revoked = format!("{}@revoked * ssh-ed25519 {}\n", plain, KEY);
+        let negated = format!("*.example.org,!git.example.org ssh-ed25519 {}\n", KEY);
+
+        let checks = vec![
+            (&*plain, "example.com", 22, HostKeyStatus::Known),
+            (&*plain, "EXAMPLE.com", 22, HostKeyStatus::Known),
+            (&*plain, "git.example.org", 22, HostKeyStatus::Known),
+            (&*plain, "example.com", 2222, HostKeyStatus::Unknown),
+            (&*plain, "github.com", 22, HostKeyStatus::Unknown),
+            (&*hashed, "example.com", 22, HostKeyStatus::Known),
+            (&*hashed, "example.com", 2222, HostKeyStatus::Known),
+            (&*hashed, "github.com", 22, HostKeyStatus::Unknown),
+            (&*changed, "example.com", 22, HostKeyStatus::Changed),
+            (other_type, "example.com", 22, HostKeyStatus::Unknown),
+            (&*revoked, "example.com", 22, HostKeyStatus::Changed),
+            (&*negated, "foo.example.org", 22, HostKeyStatus::Known),
+            (&*negated, "git.example.org", 22, HostKeyStatus::Unknown),
+            ("", "example.com", 22, HostKeyStatus::Unknown),
+        ];
+        for (contents, host, port, expected) in checks {
+            assert_eq!(check(contents, host, port, &key), expected, "{}:{} in:\n{}", host, port, contents);
+        }
+    }
+
+}
@@ -0,0 +1,82 @@
+use std::path::Path;
+use crate::error::Error;
+use super::{ Opts, LfsOutcome };
+use super::command::{ git, run, run_with_timeout };
+
+/// Set in the config of a backup while we're fetching LFS objects, and
+/// removed once we've fetched them all, so that we know to try again
+/// next time if we don't manage to.
+const PENDING_KEY: &str = "git-backup.lfsPending";
+
+/// Fetch the LFS objects for every ref in a backup, if we've just fetched
+/// anything or didn't manage to fetch them all last time.
+pub fn sync(opts: &Opts, fetched: bool) -> Option<LfsOutcome> {
+    if !fetched && !is_pending(opts) {
+        return None
+    }
+    let objects = opts.destination.join("lfs").join("objects");
+    let before = dir_size(&objects);
+    let outcome = match fetch_all(opts) {
+        Ok(()) => {
+            let size = dir_size(&objects);
+            LfsOutcome::Fetched { size, fetched: size.saturating_sub(before) }
+        },
+        Err(e) => LfsOutcome::Failed { error: e.to_string() }
+    };
+    Some(outcome)
+}
+
+/// The equivalent of `git lfs fetch --all origin`. Our credentials are
+/// handed to git-lfs in the same way as they are to git.
+fn fetch_all(opts: &Opts) -> Result<(),Error> {
+    run(git(opts).args(["config", PENDING_KEY, "true"]))?;
+    // git-lfs only reports its progress to a terminal unless told otherwise:
+    run_with_timeout(git(opts).args(["lfs", "fetch", "--all", "origin"]).env("GIT_LFS_FORCE_PROGRESS", "1"), opts.timeout)?;
+    run(git(opts).args(["config", "--unset", PENDING_KEY]))?;
+    Ok(())
+}
+
+fn is_pending(opts: &Opts) -> bool {
+    git(opts)
+        .args(["config", "--get", PENDING_KEY])
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+/// The total size of the files in a folder, which is 0 if it doesn't exist.
+pub(super) fn dir_size(path: &Path) -> u64 {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.file_type() {
+            Ok(t) if t.is_dir() => dir_size(&entry.path()),
+            Ok(t) if t.is_file() => entry.metadata().map(|m| m.len()).unwrap_or(0),
+            _ => 0
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_dir_size() {
+        let dir = std::env::temp_dir().join(format!("git-backup-test-lfs-{}", std::process::id()));
+        assert_eq!(dir_size(&dir), 0);
+
+        std::fs::create_dir_all(dir.join("ab/cd")).unwrap();
+        std::fs::write(dir.join("ab/cd/abcd1234"), vec![0; 1000]).unwrap();
+        std::fs::write(dir.join("ab/cd/abcd5678"), vec![0; 24]).unwrap();
+        std::fs::write(dir.join("top"), vec![0; 3]).unwrap();
+        assert_eq!(dir_size(&dir), 1027);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
@@ -0,0 +1,217 @@
+use std::path::Path;
+use serde::{ Serialize, Deserialize };
+use chrono::{ DateTime, Duration, Utc };
+use crate::error::Error;
+use super::{ backend, path_hash, Opts };
+use super::command::{ git, run };
+use super::lfs::dir_size;
+
+/// The config key that records when a full `git gc` was last run on a backup.
+const LAST_GC_KEY: &str = "git-backup.lastGc";
+
+/// Which maintenance tasks to run on each backup once it's been synced.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenancePolicy {
+    /// Run maintenance on backups at all, unless a source or the
+    /// command line says otherwise
+    pub enabled: bool,
+    /// Pack up loose objects, and combine small packs into larger
+    /// ones, whenever anything is fetched
+    pub repack: bool,
+    /// Write a multi-pack-index, so that objects are quick to find
+    /// however many packs there are
+    pub multi_pack_index: bool,
+    /// Write a commit-graph, so that history is quick to walk
+    pub commit_graph: bool,
+    /// Run a full `git gc` every this many days, or never if 0
+    pub gc_days: u32
+}
+
+impl Default for MaintenancePolicy {
+    fn default() -> MaintenancePolicy {
+        MaintenancePolicy { enabled: false, repack: true, multi_pack_index: true, commit_graph: true, gc_days: 30 }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq,Eq,Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum MaintenanceOutcome {
+    Done {
+        /// The tasks that were run, eg `repack` or `gc`
+        tasks: Vec<String>,
+        /// The size (in bytes) of the objects in the backup beforehand
+        size_before: u64,
+        /// The size (in bytes) of the objects in the backup afterwards
+        size_after: u64
+    },
+    Failed { error: String }
+}
+
+impl MaintenanceOutcome {
+    /// How much space (in bytes) was freed up.
+    pub fn reclaimed(&self) -> u64 {
+        match self {
+            MaintenanceOutcome::Done { size_before, size_after, .. } => size_before.saturating_sub(*size_after),
+            MaintenanceOutcome::Failed { .. } => 0
+        }
+    }
+}
+
+/// Run whichever maintenance tasks are due on a backup. Nothing needs doing
+/// to a backup that we haven't fetched anything into, unless it's time for
+/// its occasional full gc.
+pub fn run_due(opts: &Opts, policy: &MaintenancePolicy, fetched: bool, now: DateTime<Utc>) -> Option<MaintenanceOutcome> {
+    let gc_due = match is_gc_due(opts, policy, now) {
+        Ok(gc_due) => gc_due,
+        Err(e) => return Some(MaintenanceOutcome::Failed { error: e.to_string() })
+    };
+    if !fetched && !gc_due {
+        return None
+    }
+
+    // A full gc repacks everything, so there's no need to repack as well:
+    let mut tasks = vec![];
+    if gc_due {
+        tasks.push("gc");
+    } else if policy.repack {
+        tasks.push("repack");
+    }
+    if policy.multi_pack_index {
+        tasks.push("multi-pack-index");
+    }
+    if policy.commit_graph {
+        tasks.push("commit-graph");
+    }
+    if tasks.is_empty() {
+        return None
+    }
+
+    let objects = opts.destination.join("objects");
+    let size_before = dir_size(&objects);
+    for task in &tasks {
+        if let Err(e) = run_task(opts, task) {
+            return Some(MaintenanceOutcome::Failed { error: format!("{} failed: {}", task, e) })
+        }
+    }
+    if gc_due {
+        if let Err(e) = backend::set_config(opts, LAST_GC_KEY, &now.to_rfc3339()) {
+            return Some(MaintenanceOutcome::Failed { error: e.to_string() })
+        }
+    }
+    Some(MaintenanceOutcome::Done {
+        tasks: tasks.into_iter().map(|t| t.to_owned()).collect(),
+        size_before,
+        size_after: dir_size(&objects)
+    })
+}
+
+fn run_task(opts: &Opts, task: &str) -> Result<(),Error> {
+    let mut cmd = git(opts);
+    match task {
+        // Unreachable objects are only pruned once they're a couple of weeks
+        // old, and anything we want to keep has a ref pointing to it:
+        "gc" => cmd.args(["gc", "--quiet"]),
+        // Loose objects are rolled up along with any packs that are small
+        // compared to the others, so the work done stays in proportion to
+        // what's been fetched. Git can't do this in partial clones, so
+        // only the loose objects are packed up in those. Objects borrowed
+        // from a shared pool are left where they are:
+        "repack" if has_pack_file(opts.destination, "promisor") => cmd.args(["repack", "-d", "-l", "-q"]),
+        "repack" => cmd.args(["repack", "-d", "-l", "-q", "--geometric=2"]),
+        // Backups that borrow all of their objects from a shared pool may
+        // not have any packs of their own to index:
+        "multi-pack-index" if !has_pack_file(opts.destination, "pack") => return Ok(()),
+        "multi-pack-index" => cmd.args(["multi-pack-index", "write"]),
+        // Splitting the commit-graph means only new commits need writing:
+        "commit-graph" => cmd.args(["commit-graph", "write", "--reachable", "--split"]),
+        _ => return Err(err!("Unknown maintenance task '{}'", task))
+    };
+    run(&mut cmd)?;
+    Ok(())
+}
+
+/// Does the backup have any pack files with the extension given? Partial clones
+/// keep the objects fetched from the remote in "promisor" packs, which are
+/// marked by a `.promisor` file alongside each one.
+fn has_pack_file(path: &Path, extension: &str) -> bool {
+    std::fs::read_dir(path.join("objects").join("pack"))
+        .map(|entries| entries.filter_map(|e| e.ok()).any(|e| e.path().extension() == Some(extension.as_ref())))
+        .unwrap_or(false)
+}
+
+/// Is it time for a full gc? Backups that we haven't run one on before are
+/// given a last gc at some point in the last interval, based on where they
+/// live, so that they don't all become due on the same night.
+fn is_gc_due(opts: &Opts, policy: &MaintenancePolicy, now: DateTime<Utc>) -> Result<bool,Error> {
+    if policy.gc_days == 0 {
+        return Ok(false)
+    }
+    let interval = Duration::days(policy.gc_days as i64);
+    let last_gc = match backend::get_config(opts, LAST_GC_KEY)?.and_then(|t| DateTime::parse_from_rfc3339(&t).ok()) {
+        Some(last_gc) => last_gc.with_timezone(&Utc),
+        None => {
+            let last_gc = now - Duration::seconds((path_hash(opts.destination) % interval.num_seconds() as u64) as i64);
+            backend::set_config(opts, LAST_GC_KEY, &last_gc.to_rfc3339())?;
+            last_gc
+        }
+    };
+    Ok(now - last_gc >= interval)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use super::super::test_fixtures::{ run_git, test_opts };
+
+    #[test]
+    fn test_run_due() {
+        let dir = std::env::temp_dir().join(format!("git-backup-test-maintenance-{}", std::process::id()));
+        let backup = dir.join("backup.git");
+        std::fs::create_dir_all(&backup).unwrap();
+        run_git(&backup, &["init", "--quiet", "--bare"]);
+        let tree = run_git(&backup, &["write-tree"]);
+        for message in &["first", "second", "third"] {
+            let commit = run_git(&backup, &["commit-tree", &tree, "-m", message]);
+            run_git(&backup, &["update-ref", &format!("refs/heads/{}", message), &commit]);
+        }
+
+        let opts = test_opts(&backup);
+        let policy = MaintenancePolicy { gc_days: 10, ..MaintenancePolicy::default() };
+        let now = Utc::now();
+        let count_loose = || run_git(&backup, &["count-objects"]).split_whitespace().next().unwrap().parse::<u32>().unwrap();
+        assert!(count_loose() > 0);
+
+        // Backups are given a last gc somewhere in the interval:
+        let outcome = run_due(&opts, &policy, true, now).unwrap();
+        let tasks = match &outcome {
+            MaintenanceOutcome::Done { tasks, .. } => tasks.clone(),
+            MaintenanceOutcome::Failed { error } => panic!("maintenance failed: {}", error)
+        };
+        assert_eq!(tasks, vec!["repack", "multi-pack-index", "commit-graph"]);
+        assert_eq!(count_loose(), 0);
+        assert!(backup.join("objects/info/commit-graphs").is_dir());
+        let last_gc = backend::get_config(&opts, LAST_GC_KEY).unwrap().unwrap();
+        let last_gc = DateTime::parse_from_rfc3339(&last_gc).unwrap().with_timezone(&Utc);
+        assert!(last_gc <= now && now - last_gc < Duration::days(10));
+
+        // Nothing is due until we fetch something, or it's time for a gc:
+        assert_eq!(run_due(&opts, &policy, false, now), None);
+        let outcome = run_due(&opts, &policy, false, last_gc + Duration::days(10)).unwrap();
+        assert!(matches!(outcome, MaintenanceOutcome::Done { ref tasks, .. } if tasks[0] == "gc"), "{:?}", outcome);
+        assert_eq!(run_due(&opts, &policy, false, last_gc + Duration::days(19)), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_gc_is_spread_out() {
+        let interval = Duration::days(30).num_seconds() as u64;
+        let days: std::collections::HashSet<u64> = (0..100)
+            .map(|n| path_hash(Path::new(&format!("/backups/github-jsdw/repo-{}.git", n))) % interval / 86400)
+            .collect();
+        assert!(days.len() > 20, "backups should be spread across the interval, but only hit {} days", days.len());
+    }
+
+}
@@ -0,0 +1,1129 @@
+// Repositories are synced by running the system installation of git,
+// or using libgit2 if built with the 'native-git' feature. LFS objects
+// are always fetched by running git, since libgit2 knows nothing of them:
+#[cfg_attr(feature = "native-git", allow(dead_code))]
+mod command;
+#[cfg(feature = "native-git")]
+mod native;
+#[cfg(feature = "native-git")]
+mod known_hosts;
+mod lfs;
+mod submodules;
+mod repair;
+mod maintenance;
+mod pool;
+mod bundle;
+
+use regex::Regex;
+use lazy_static::lazy_static;
+use serde::{ Serialize, Deserialize };
+use chrono::{ DateTime, Utc };
+use std::fmt;
+use std::str::FromStr;
+use std::convert::TryFrom;
+use std::process::Command;
+use std::time::Duration;
+use std::path::{ Path, PathBuf };
+use std::collections::{ BTreeMap, BTreeSet };
+use crate::error::Error;
+use crate::filter::{ parse_size, format_size };
+
+#[cfg(not(feature = "native-git"))]
+use command as backend;
+#[cfg(feature = "native-git")]
+use native as backend;
+
+pub use submodules::{ url_key, check_url, shares_credentials, shares_ssh_key };
+pub use repair::Repair;
+pub use maintenance::{ MaintenancePolicy, MaintenanceOutcome };
+pub use pool::{ SharingOutcome, leave_pool };
+pub use bundle::{ BundleTarget, BundleOutcome, reassemble };
+
+#[derive(Debug,Clone,Copy,PartialOrd,Ord,PartialEq,Eq)]
+pub struct Version {
+    major: u8,
+    minor: u8,
+    patch: u8
+}
+
+impl Version {
+    pub fn new(major: u8, minor: u8, patch: u8) -> Version {
+        Version { major, minor, patch }
+    }
+}
+
+pub fn version() -> Result<Version,Error> {
+    lazy_static! {
+        static ref GIT_VERSION_RE: Regex = Regex::new("([0-9]+)\\.([0-9]+)\\.([0-9]+)").unwrap();
+    }
+    let out = Command::new("git")
+        .arg("version")
+        .output()?;
+
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let caps = GIT_VERSION_RE.captures(&stdout).ok_or_else(|| err!("Cannot parse version from {}", &stdout))?;
+
+    let major = caps.get(1).unwrap().as_str().parse().unwrap();
+    let minor = caps.get(2).unwrap().as_str().parse().unwrap();
+    let patch = caps.get(3).unwrap().as_str().parse().unwrap();
+
+    Ok(Version { major, minor, patch })
+}
+
+/// Check that we're able to sync repositories. When built with the
+/// `native-git` feature we don't need git to be installed at all.
+pub fn check_available() -> Result<(),Error> {
+    if cfg!(feature = "native-git") {
+        return Ok(())
+    }
+    // 2.9.0 is needed to be able to clear out any existing credential helpers:
+    let git_version = version().map_err(|_| err!("Git does not appear to be installed"))?;
+    if git_version < Version::new(2,9,0) {
+        return Err(err!("Your version of git appears to be too old. This command requires at least 2.9.0"))
+    }
+    Ok(())
+}
+
+/// Check that we're able to fetch LFS objects.
+pub fn check_lfs_available() -> Result<(),Error> {
+    let available = Command::new("git")
+        .args(["lfs", "version"])
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false);
+    if !available {
+        return Err(err!("Git LFS does not appear to be installed, but is needed to back up LFS objects"))
+    }
+    Ok(())
+}
+
+/// Check that we're able to share objects between backups, which always needs git.
+pub fn check_sharing_available() -> Result<(),Error> {
+    version().map_err(|_| err!("Git does not appear to be installed, but is needed to share objects between backups"))?;
+    Ok(())
+}
+
+/// Check that we're able to bundle up backups, which always needs git.
+pub fn check_bundles_available() -> Result<(),Error> {
+    version().map_err(|_| err!("Git does not appear to be installed, but is needed to bundle up backups"))?;
+    Ok(())
+}
+
+/// Check that we're able to run maintenance on backups, which always needs
+/// git. 2.33.0 is needed to combine packs geometrically.
+pub fn check_maintenance_available() -> Result<(),Error> {
+    let git_version = version().map_err(|_| err!("Git does not appear to be installed, but is needed to run maintenance on backups"))?;
+    if git_version < Version::new(2,33,0) {
+        return Err(err!("Your version of git appears to be too old to run maintenance on backups. This requires at least 2.33.0"))
+    }
+    Ok(())
+}
+
+/// Older versions of this tool saved a credential helper into the config of
+/// each backup. This matches it, so that it can be removed again.
+const OLD_CREDENTIAL_HELPER_RE: &str = r#"sleep 1; echo "username=\$\{GIT_USER\}""#;
+
+/// The protocol and host (including any port) that we expect to be asked
+/// for credentials for when fetching from the URL given. Credentials are
+/// never handed out for anything else, like a host that we're redirected to.
+fn credential_target(url: &str) -> Option<(String,String)> {
+    let url = url::Url::parse(url).ok()?;
+    let host = url.host_str()?;
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_owned()
+    };
+    Some((url.scheme().to_owned(), host))
+}
+
+/// The config key that records the clone strategy that a backup uses.
+const STRATEGY_KEY: &str = "git-backup.strategy";
+
+/// Refs whose history would otherwise be lost are kept under here.
+const PRESERVED_REFS_PREFIX: &str = "refs/git-backup/";
+
+/// The name and object ID of each ref in a repository.
+type Refs = BTreeMap<String,String>;
+
+/// What a remote repository told us about itself before we fetched anything.
+struct RemoteRefs {
+    /// The branch that HEAD points to, if any
+    head: Option<String>,
+    refs: Refs
+}
+
+#[derive(Clone,Copy)]
+pub struct Opts<'a> {
+    pub repo_url: &'a str,
+    pub username: &'a str,
+    pub password: &'a str,
+    pub destination: &'a Path,
+    /// If provided, the repository is cloned over SSH using these.
+    pub ssh: Option<&'a SshOpts>,
+    /// Which refs to back up.
+    pub refs: RefSelection,
+    /// How to clone the repository. Existing backups carry on using
+    /// whichever strategy they were cloned with.
+    pub strategy: CloneStrategy,
+    /// The default branch of the repository, if the service told us.
+    pub default_branch: Option<&'a str>,
+    /// Give up on anything that talks to the remote if it takes longer than
+    /// this. libgit2 can only stop transfers that are still making progress.
+    pub timeout: Option<Duration>,
+    /// Fetch even if the refs in the remote repository match ours.
+    pub force_fetch: bool,
+    /// Fetch the LFS objects for every ref, too.
+    pub lfs: bool,
+    /// Find the submodules that every ref refers to.
+    pub submodules: bool,
+    /// Check that the backup is intact and has every ref that the
+    /// remote told us about, once it's been synced.
+    pub verify: bool,
+    /// If the backup turns out to be corrupt, move it into this folder,
+    /// clone it again, and salvage what we can from the damaged copy.
+    pub quarantine: Option<&'a Path>,
+    /// Share objects with related backups through pool repositories
+    /// kept in this folder, if the backup is a full clone.
+    pub object_pools: Option<&'a Path>,
+    /// Tidy up the backup according to this policy once it's been synced.
+    pub maintenance: Option<MaintenancePolicy>,
+    /// Write every ref in the backup into bundles here once it's been
+    /// synced, if the backup is a full clone.
+    pub bundle: Option<BundleTarget<'a>>,
+    /// Called every so often while objects are being received, if
+    /// we're able to tell how the transfer is progressing (we can't
+    /// when running the system installation of git).
+    #[cfg_attr(not(feature = "native-git"), allow(dead_code))]
+    pub on_progress: &'a dyn Fn(&Progress)
+}
+
+/// Which refs in a repository to back up.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RefSelection {
+    /// Every ref, including things like pull requests (`refs/pull/*`)
+    All,
+    /// Branches and tags
+    BranchesAndTags,
+    /// Just the default branch
+    DefaultBranch
+}
+
+impl RefSelection {
+    /// Is the ref given one that we back up?
+    fn includes(self, name: &str, default_branch: &str) -> bool {
+        match self {
+            RefSelection::All => name.starts_with("refs/") && !name.starts_with(PRESERVED_REFS_PREFIX),
+            RefSelection::BranchesAndTags => name.starts_with("refs/heads/") || name.starts_with("refs/tags/"),
+            RefSelection::DefaultBranch => name.strip_prefix("refs/heads/") == Some(default_branch)
+        }
+    }
+    /// The refspecs to fetch in order to back up these refs.
+    fn refspecs(self, default_branch: &str) -> Vec<String> {
+        match self {
+            // Our own refs don't exist upstream, so keep them out of the prune:
+            RefSelection::All => vec![
+                "+refs/*:refs/*".to_owned(),
+                format!("^{}*", PRESERVED_REFS_PREFIX)
+            ],
+            RefSelection::BranchesAndTags => vec![
+                "+refs/heads/*:refs/heads/*".to_owned(),
+                "+refs/tags/*:refs/tags/*".to_owned()
+            ],
+            RefSelection::DefaultBranch => vec![
+                format!("+refs/heads/{0}:refs/heads/{0}", default_branch)
+            ]
+        }
+    }
+}
+
+impl FromStr for RefSelection {
+    type Err = String;
+    fn from_str(s: &str) -> Result<RefSelection,String> {
+        match s {
+            "all" => Ok(RefSelection::All),
+            "branches-and-tags" => Ok(RefSelection::BranchesAndTags),
+            "default-branch" => Ok(RefSelection::DefaultBranch),
+            _ => Err(format!("'{}' is not valid; expected one of all, branches-and-tags or default-branch", s))
+        }
+    }
+}
+
+/// How much of a repository to clone. Anything other than a full clone
+/// leaves out some of the repository, so isn't a complete backup.
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq,Serialize,Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum CloneStrategy {
+    /// Everything in the refs that we back up
+    #[default]
+    Full,
+    /// Leave out blobs larger than this many bytes (a partial clone)
+    BlobLimit(u64),
+    /// Only this many commits of history from each ref (a shallow clone)
+    Depth(u32),
+    /// Only the default branch
+    SingleBranch
+}
+
+impl CloneStrategy {
+    pub fn is_full(self) -> bool {
+        self == CloneStrategy::Full
+    }
+}
+
+impl FromStr for CloneStrategy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<CloneStrategy,String> {
+        let (name, value) = match s.find('=') {
+            Some(idx) => (&s[..idx], Some(&s[idx+1..])),
+            None => (s, None)
+        };
+        match (name, value) {
+            ("full", None) => Ok(CloneStrategy::Full),
+            ("single-branch", None) => Ok(CloneStrategy::SingleBranch),
+            ("blob-limit", Some(size)) => parse_size(size).map(CloneStrategy::BlobLimit),
+            ("depth", Some(depth)) => match depth.parse() {
+                Ok(depth) if depth > 0 => Ok(CloneStrategy::Depth(depth)),
+                _ => Err(format!("'{}' is not a valid depth; expected a number greater than 0", depth))
+            },
+            _ => Err(format!("'{}' is not valid; expected one of full, blob-limit=<size>, depth=<commits> or single-branch", s))
+        }
+    }
+}
+
+impl TryFrom<String> for CloneStrategy {
+    type Error = String;
+    fn try_from(s: String) -> Result<CloneStrategy,String> {
+        s.parse()
+    }
+}
+
+impl From<CloneStrategy> for String {
+    fn from(strategy: CloneStrategy) -> String {
+        strategy.to_string()
+    }
+}
+
+impl fmt::Display for CloneStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CloneStrategy::Full => write!(f, "full"),
+            CloneStrategy::BlobLimit(size) => write!(f, "blob-limit={}", format_size(*size)),
+            CloneStrategy::Depth(depth) => write!(f, "depth={}", depth),
+            CloneStrategy::SingleBranch => write!(f, "single-branch")
+        }
+    }
+}
+
+/// How to authenticate when cloning over SSH.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct SshOpts {
+    /// The private key to use (eg a deploy key)
+    pub key: PathBuf,
+    /// Only trust hosts whose keys are listed in this file, rather
+    /// than in the user's `~/.ssh/known_hosts`
+    pub known_hosts: Option<PathBuf>
+}
+
+/// How far through receiving a repository we are.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Progress {
+    pub received_objects: usize,
+    pub total_objects: usize,
+    pub received_bytes: usize
+}
+
+/// What happened when we synced a repository.
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct SyncOutcome {
+    /// Did we fetch anything? We don't if nothing has changed upstream.
+    pub fetched: bool,
+    /// Was an unfinished backup at the destination cloned again?
+    pub recloned: bool,
+    /// The strategy that the backup was cloned with, which may not
+    /// be the one that we asked for if it already existed
+    pub strategy: CloneStrategy,
+    /// Refs that were force-pushed or deleted upstream, and
+    /// where their old history has been kept
+    pub preserved: Vec<PreservedRef>,
+    /// What happened when fetching LFS objects, if we tried to. Failing
+    /// to fetch them doesn't fail the sync.
+    pub lfs: Option<LfsOutcome>,
+    /// The URLs of the submodules that any ref refers to, if we were
+    /// asked for them
+    pub submodules: Vec<String>,
+    /// What we found when checking the backup, if we were asked to
+    pub verification: Option<Verification>,
+    /// What we did about the backup being corrupt, if it was
+    pub repair: Option<Repair>,
+    /// What happened when sharing objects with related backups, if we
+    /// tried to. Failing to do so doesn't fail the sync.
+    pub sharing: Option<SharingOutcome>,
+    /// What happened when tidying up the backup, if anything was due.
+    /// Failing to do so doesn't fail the sync.
+    pub maintenance: Option<MaintenanceOutcome>,
+    /// What happened when bundling up the backup, if its refs had changed.
+    /// Failing to do so doesn't fail the sync.
+    pub bundle: Option<BundleOutcome>
+}
+
+#[derive(Debug,Clone,PartialEq,Eq,Serialize)]
+pub struct PreservedRef {
+    /// The ref that was changed upstream, eg `refs/heads/master`
+    pub name: String,
+    /// The ref that now points to its old history, eg
+    /// `refs/git-backup/20191001T120000Z/heads/master`
+    pub preserved_as: String,
+    /// Was the ref deleted (rather than force-pushed)?
+    pub deleted: bool
+}
+
+#[derive(Debug,Clone,PartialEq,Eq,Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum LfsOutcome {
+    Fetched {
+        /// The size (in bytes) of the LFS objects that we have
+        size: u64,
+        /// How much of that (in bytes) was fetched just now
+        fetched: u64
+    },
+    Failed { error: String }
+}
+
+/// What we found when checking that a backup is intact.
+#[derive(Debug,Clone,PartialEq,Eq,Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum Verification {
+    /// Every object that can be reached from a ref is there and intact
+    Ok,
+    /// Objects are missing or corrupt
+    Corrupt { error: String },
+    /// Refs that the remote told us about are missing from the backup
+    Incomplete { missing: Vec<String> }
+}
+
+impl Verification {
+    pub fn is_ok(&self) -> bool {
+        *self == Verification::Ok
+    }
+}
+
+/// Create a bare copy of the repository at the destination given, or
+/// bring an existing copy up to date.
+pub fn sync_repository(opts: Opts) -> Result<SyncOutcome,Error> {
+    let quarantine = match opts.quarantine {
+        Some(quarantine) if opts.destination.join("HEAD").is_file() => quarantine,
+        _ => return sync(opts)
+    };
+
+    // Syncing can remove refs from a damaged backup before we notice that
+    // it's damaged, so note them down in case we need to salvage them:
+    let refs = backend::refs(&opts).ok();
+    let result = sync(opts);
+
+    // Only replace a backup if git agrees that it's damaged:
+    let reason = match &result {
+        Err(e) if repair::looks_corrupt(&e.to_string()) => backend::fsck(opts.destination).err().map(|e| e.to_string()),
+        Ok(SyncOutcome { verification: Some(Verification::Corrupt { error }), .. }) => Some(error.to_owned()),
+        _ => None
+    };
+    match reason {
+        Some(reason) => repair::repair(opts, quarantine, reason, refs),
+        None => result
+    }
+}
+
+fn sync(opts: Opts) -> Result<SyncOutcome,Error> {
+
+    // Services hand us these URLs, so make sure they're sane:
+    validate_url(opts.repo_url)
+        .map_err(|e| err!("Refusing to sync from '{}': {}", opts.repo_url, e))?;
+
+    // New backups are cloned alongside the destination and only moved into
+    // place once they're complete. Anything left there by a run that was
+    // interrupted is of no use:
+    let incomplete = incomplete_path(opts.destination);
+    if incomplete.exists() {
+        std::fs::remove_dir_all(&incomplete).map_err(|e|
+            err!("Could not remove unfinished clone '{}': {}", incomplete.to_string_lossy(), e)
+        )?;
+    }
+
+    // Is the folder already a bare repo? It is if it contains a file called HEAD,
+    // but older versions of this tool cloned in place and may not have finished.
+    // There's nothing worth keeping in those, so clone them again:
+    let mut is_repo = opts.destination.join("HEAD").is_file();
+    let recloned = is_repo && is_unfinished(opts.destination);
+    if recloned {
+        std::fs::remove_dir_all(opts.destination).map_err(|e|
+            err!("Could not remove unfinished backup '{}': {}", opts.destination.to_string_lossy(), e)
+        )?;
+        is_repo = false;
+    }
+
+    let (mut outcome, missing_refs) = if is_repo {
+        fetch_refs(&opts, false)?
+    } else {
+        // Like 'git clone', refuse to clone into a folder that has things in it:
+        if opts.destination.is_dir() {
+            let is_empty = std::fs::read_dir(opts.destination)?.next().is_none();
+            if !is_empty {
+                return Err(err!("Destination '{}' already exists and is not an empty folder", opts.destination.to_string_lossy()))
+            }
+            std::fs::remove_dir(opts.destination)?;
+        }
+        std::fs::create_dir_all(&incomplete).map_err(|e|
+            err!("Could not create path '{}': {}", incomplete.to_string_lossy(), e)
+        )?;
+        let clone_opts = Opts { destination: &incomplete, ..opts };
+        let cloned = backend::init(&clone_opts)
+            .and_then(|_| backend::set_config(&clone_opts, STRATEGY_KEY, &opts.strategy.to_string()))
+            .and_then(|_| fetch_refs(&clone_opts, true))
+            .and_then(|outcome| {
+                std::fs::rename(&incomplete, opts.destination)?;
+                Ok(outcome)
+            });
+        if cloned.is_err() {
+            let _ = std::fs::remove_dir_all(&incomplete);
+        }
+        cloned?
+    };
+    outcome.recloned = recloned;
+
+    if opts.lfs {
+        outcome.lfs = lfs::sync(&opts, outcome.fetched);
+    }
+    if opts.submodules {
+        outcome.submodules = list_submodules(&opts)?;
+    }
+    if let Some(pools) = opts.object_pools {
+        // Partial and shallow clones are missing objects that other backups may need:
+        if outcome.strategy.is_full() {
+            outcome.sharing = pool::share(&opts, pools, outcome.fetched);
+        }
+    }
+    if let Some(policy) = &opts.maintenance {
+        outcome.maintenance = maintenance::run_due(&opts, policy, outcome.fetched, Utc::now());
+    }
+    if opts.verify {
+        outcome.verification = Some(verify(opts.destination, missing_refs));
+    }
+    if let Some(bundle) = opts.bundle {
+        // Partial and shallow clones can't be bundled, since they're missing
+        // objects, and there's no point bundling a backup that's corrupt:
+        let is_corrupt = matches!(outcome.verification, Some(Verification::Corrupt { .. }));
+        if outcome.strategy.is_full() && !is_corrupt {
+            outcome.bundle = bundle::export(&opts, bundle);
+        }
+    }
+    Ok(outcome)
+}
+
+/// Check that the objects in a backup are intact, and that every ref that
+/// can be reached has everything that it needs (unless it was cloned without
+/// them on purpose).
+pub fn verify_repository(path: &Path) -> Verification {
+    verify(path, vec![])
+}
+
+fn verify(path: &Path, missing_refs: Vec<String>) -> Verification {
+    match backend::fsck(path) {
+        Err(e) => Verification::Corrupt { error: e.to_string() },
+        Ok(()) if !missing_refs.is_empty() => Verification::Incomplete { missing: missing_refs },
+        Ok(()) => Verification::Ok
+    }
+}
+
+/// Is the folder given a backup, as opposed to a folder of them?
+pub fn is_backup(path: &Path) -> bool {
+    path.extension().map(|ext| ext == "git").unwrap_or(false)
+        && path.join("HEAD").is_file()
+        && path.join("objects").is_dir()
+}
+
+/// Fetch whatever has changed upstream into the repository at the destination,
+/// keeping any history that this would lose. New repositories are always fetched.
+/// Also hands back any refs that the remote told us about that we don't have.
+fn fetch_refs(opts: &Opts, is_new: bool) -> Result<(SyncOutcome,Vec<String>),Error> {
+
+    // Switching strategy would leave a backup that's a mixture of the two, so
+    // keep using the one that it was cloned with. Older backups are all full:
+    let strategy = match backend::get_config(opts, STRATEGY_KEY)? {
+        Some(strategy) => strategy.parse()
+            .map_err(|e| err!("Could not understand the clone strategy recorded in the backup: {}", e))?,
+        None => CloneStrategy::Full
+    };
+    let ref_selection = if strategy == CloneStrategy::SingleBranch { RefSelection::DefaultBranch } else { opts.refs };
+
+    // The URL may have changed since we last synced, and older
+    // versions of this tool left things behind that need tidying up:
+    backend::configure(opts)?;
+
+    // Find out what the remote has, so that we know its default branch,
+    // and whether anything has changed since we last fetched from it:
+    let remote = backend::ls_remote(opts)?;
+    let default_branch = remote.head.or_else(|| opts.default_branch.map(|b| b.to_owned()));
+    if ref_selection == RefSelection::DefaultBranch && default_branch.is_none() {
+        return Err(err!("Cannot back up only the default branch, because we couldn't find out what it is"))
+    }
+    let default_branch_name = default_branch.as_deref().unwrap_or("");
+
+    let before = backend::refs(opts)?;
+    let selected = |refs: &Refs| -> Refs {
+        refs.iter()
+            .filter(|(name, _)| ref_selection.includes(name, default_branch_name))
+            .map(|(name, id)| (name.to_owned(), id.to_owned()))
+            .collect()
+    };
+    let fetched = is_new || opts.force_fetch || selected(&remote.refs) != selected(&before);
+
+    let mut preserved = vec![];
+    let mut after = before.clone();
+    if fetched {
+        // Keep whatever the remote says has changed before fetching, so that
+        // it's still kept if the fetch fails partway through. Once we've
+        // fetched, we can tell which branches have only moved forwards:
+        let is_shallow = matches!(strategy, CloneStrategy::Depth(_));
+        let now = Utc::now();
+        let mut expected: Refs = before.iter()
+            .filter(|(name, _)| !ref_selection.includes(name, default_branch_name))
+            .map(|(name, id)| (name.to_owned(), id.to_owned()))
+            .collect();
+        expected.extend(selected(&remote.refs));
+        let kept = preserve_history(opts, &before, &expected, is_shallow, now)?;
+
+        backend::fetch(opts, &ref_selection.refspecs(default_branch_name), strategy)?;
+        after = backend::refs(opts)?;
+        preserved = preserve_history(opts, &before, &after, is_shallow, now)?;
+        for r in kept.iter().filter(|r| !preserved.iter().any(|p| p.preserved_as == r.preserved_as)) {
+            backend::delete_ref(opts, &r.preserved_as)?;
+        }
+    }
+    let missing_refs = selected(&remote.refs).into_keys()
+        .filter(|name| !after.contains_key(name))
+        .collect();
+
+    // Like 'git clone', point HEAD at the default branch:
+    if let (true, Some(branch)) = (is_new, &default_branch) {
+        backend::set_head(opts, branch)?;
+    }
+
+    Ok((SyncOutcome { fetched, strategy, preserved, ..SyncOutcome::default() }, missing_refs))
+}
+
+/// Where a new backup is cloned to before it's moved into place at the destination.
+/// This doesn't end in '.git', so that it's never mistaken for a backup to prune.
+fn incomplete_path(destination: &Path) -> PathBuf {
+    let mut name = destination.file_name().unwrap_or_default().to_owned();
+    name.push(".incomplete");
+    destination.with_file_name(name)
+}
+
+/// Does a bare repository look like it was never finished being created? `git init`
+/// writes HEAD before the config, and the objects folder may not exist yet either.
+fn is_unfinished(path: &Path) -> bool {
+    lazy_static! {
+        static ref HEAD_RE: Regex = Regex::new("^(ref: refs/\\S+|[0-9a-f]{40}|[0-9a-f]{64})\n?$").unwrap();
+    }
+    let head = std::fs::read_to_string(path.join("HEAD")).unwrap_or_default();
+    !HEAD_RE.is_match(&head)
+        || !path.join("config").is_file()
+        || !path.join("objects").is_dir()
+        || !path.join("refs").is_dir()
+}
+
+/// Look in the `.gitmodules` file of every ref for submodules, so that we can
+/// back them up too. Relative URLs are resolved against the repository's URL.
+fn list_submodules(opts: &Opts) -> Result<Vec<String>,Error> {
+    let urls: BTreeSet<String> = backend::gitmodules(opts)?
+        .iter()
+        .flat_map(|contents| submodules::parse_gitmodules(contents))
+        .filter_map(|url| submodules::resolve_url(opts.repo_url, &url))
+        .collect();
+    Ok(urls.into_iter().collect())
+}
+
+/// Fetching overwrites refs that were force-pushed upstream and removes refs that
+/// were deleted upstream. Point refs of our own at whatever they used to point to,
+/// so that we don't lose history that may now only exist in this backup. The refs
+/// are named after the time given, so doing this again for the same time and
+/// refs changes nothing.
+fn preserve_history(opts: &Opts, before: &Refs, after: &Refs, is_shallow: bool, now: DateTime<Utc>) -> Result<Vec<PreservedRef>,Error> {
+    let timestamp = now.format("%Y%m%dT%H%M%SZ");
+    let mut preserved = vec![];
+    for (name, old_id) in before {
+        if name.starts_with(PRESERVED_REFS_PREFIX) {
+            continue
+        }
+        // Branches that have only moved forwards haven't lost anything, but
+        // any other change to a tag replaces the one that we had. We can't
+        // tell until we have the new history, so until then they're kept.
+        // Shallow backups never have it, so branches that change in them
+        // are assumed to have moved forwards:
+        let deleted = match after.get(name) {
+            None => true,
+            Some(new_id) if new_id == old_id => continue,
+            Some(_) if !name.starts_with("refs/tags/") && is_shallow => continue,
+            Some(new_id) if !name.starts_with("refs/tags/") && backend::is_ancestor(opts, old_id, new_id) => continue,
+            Some(_) => false
+        };
+        let preserved_as = format!("{}{}/{}", PRESERVED_REFS_PREFIX, timestamp, name.trim_start_matches("refs/"));
+        backend::set_ref(opts, &preserved_as, old_id)?;
+        preserved.push(PreservedRef { name: name.to_owned(), preserved_as, deleted });
+    }
+    Ok(preserved)
+}
+
+/// A number derived from a path that stays the same from one run to
+/// the next (FNV-1a, since the std hasher makes no such promise).
+fn path_hash(path: &Path) -> u64 {
+    path.to_string_lossy().bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Might an error go away if we try again? Connections that are reset or time
+/// out, and servers that are briefly unavailable, are worth another go.
+pub fn is_transient(error: &str) -> bool {
+    lazy_static! {
+        static ref TRANSIENT_RE: Regex = Regex::new(&[
+            "timed out",
+            "connection (reset|refused|closed|was aborted)",
+            "temporary failure in name resolution",
+            "could not resolve host",
+            "the remote end hung up unexpectedly",
+            "unexpected disconnect",
+            "early eof",
+            "rpc failed",
+            "empty reply from server",
+            // eg 'The requested URL returned error: 503' or 'unexpected http status code: 502':
+            "(error|status code|http)[: ]+5[0-9][0-9]\\b",
+        ].join("|")).unwrap();
+    }
+    TRANSIENT_RE.is_match(&error.to_lowercase())
+}
+
+/// Check that a URL uses one of the transports that we expect repositories
+/// to be cloned over, and can't be mistaken for anything else by git (for
+/// instance, an option, or a remote helper like `ext::`).
+pub fn validate_url(url: &str) -> Result<(),Error> {
+    lazy_static! {
+        static ref SCHEME_RE: Regex = Regex::new("^([a-zA-Z][a-zA-Z0-9+.-]*)://(?:[^@/]*@)?(.)").unwrap();
+        static ref HELPER_RE: Regex = Regex::new("^[a-zA-Z0-9+.-]+::").unwrap();
+        // The scp-like syntax for SSH, eg 'git@github.com:jsdw/git-backup.git':
+        static ref SCP_RE: Regex = Regex::new("^(?:[^@/:]+@)?[a-zA-Z0-9_][a-zA-Z0-9._-]*:[^/]").unwrap();
+    }
+
+    if url.starts_with('-') {
+        return Err(err!("URLs cannot begin with '-'"))
+    }
+    if url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(err!("URLs cannot contain whitespace or control characters"))
+    }
+    if HELPER_RE.is_match(url) {
+        return Err(err!("remote helpers are not supported"))
+    }
+
+    if let Some(caps) = SCHEME_RE.captures(url) {
+        let scheme = caps[1].to_lowercase();
+        if !["https", "http", "ssh", "git+ssh", "ssh+git", "git", "file"].contains(&&*scheme) {
+            return Err(err!("the transport '{}' is not supported", scheme))
+        }
+        // Hosts beginning with '-' could be mistaken for options to ssh:
+        if &caps[2] == "-" {
+            return Err(err!("hosts cannot begin with '-'"))
+        }
+        Ok(())
+    } else if SCP_RE.is_match(url) {
+        Ok(())
+    } else {
+        Err(err!("this is not a URL that we can clone from"))
+    }
+}
+
+/// The helpers that tests of each part of syncing share.
+#[cfg(test)]
+pub(crate) mod test_fixtures {
+
+    use std::path::Path;
+    use std::process::Command;
+    use super::{ Opts, RefSelection, CloneStrategy };
+
+    /// Run git in some folder while setting up a test.
+    pub fn run_git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .env("GIT_AUTHOR_NAME", "Test")
+            .env("GIT_AUTHOR_EMAIL", "test@example.com")
+            .env("GIT_COMMITTER_NAME", "Test")
+            .env("GIT_COMMITTER_EMAIL", "test@example.com")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap().trim().to_owned()
+    }
+
+    /// Options for syncing into the destination given, with nothing extra
+    /// turned on. Tests override whatever they need to.
+    pub fn test_opts(destination: &Path) -> Opts<'_> {
+        Opts {
+            repo_url: "file:///nowhere",
+            username: "user",
+            password: "password",
+            destination,
+            ssh: None,
+            refs: RefSelection::All,
+            strategy: CloneStrategy::Full,
+            default_branch: None,
+            timeout: None,
+            force_fetch: false,
+            lfs: false,
+            submodules: false,
+            verify: false,
+            quarantine: None,
+            object_pools: None,
+            maintenance: None,
+            bundle: None,
+            on_progress: &|_| {}
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use super::test_fixtures::{ run_git, test_opts };
+
+    #[test]
+    fn test_validate_url() {
+        let valid = vec![
+            "https://github.com/jsdw/git-backup.git",
+            "HTTPS://github.com/jsdw/git-backup.git",
+            "https://user@gitlab.example.com:8443/jsdw/git-backup.git",
+            "https://[::1]/jsdw/git-backup.git",
+            "http://git.example.com/jsdw/git-backup",
+            "ssh://git@github.com/jsdw/git-backup.git",
+            "git://git.example.com/git-backup.git",
+            "file:///backups/git-backup",
+            "git@github.com:jsdw/git-backup.git",
+            "github.com:jsdw/git-backup.git",
+            // Not something we'd expect, but harmless since no shell is involved:
+            "https://github.com/jsdw/$(id)';`id`",
+        ];
+        for url in valid {
+            assert!(validate_url(url).is_ok(), "url {} should be valid", url);
+        }
+
+        let invalid = vec![
+            "",
+            "--upload-pack=touch /tmp/foo",
+            "-oProxyCommand=touch /tmp/foo",
+            "ext::sh -c touch% /tmp/foo",
+            "ext::sh",
+            "fd::17",
+            "ssh://-oProxyCommand=touch/foo",
+            "ssh://user@-oProxyCommand=touch/foo",
+            "https://github.com/jsdw/foo bar",
+            "https://github.com/jsdw/foo\nbar",
+            "https://github.com/jsdw/foo\0",
+            "ftp://example.com/foo.git",
+            "javascript://alert(1)",
+            "/home/jsdw/repo",
+            "../repo",
+            "repo",
+            "$(touch /tmp/foo)",
+            "-:foo",
+        ];
+        for url in invalid {
+            assert!(validate_url(url).is_err(), "url {:?} should be invalid", url);
+        }
+    }
+
+    #[test]
+    fn test_parse_clone_strategy() {
+        let strategies = vec![
+            ("full", CloneStrategy::Full),
+            ("blob-limit=1MB", CloneStrategy::BlobLimit(1 << 20)),
+            ("blob-limit=1500", CloneStrategy::BlobLimit(1500)),
+            ("depth=50", CloneStrategy::Depth(50)),
+            ("single-branch", CloneStrategy::SingleBranch),
+        ];
+        for (s, strategy) in strategies {
+            assert_eq!(s.parse::<CloneStrategy>(), Ok(strategy), "strategy {} parsed wrongly", s);
+            assert_eq!(strategy.to_string().parse::<CloneStrategy>(), Ok(strategy), "strategy {} did not round trip", s);
+        }
+        assert_eq!(CloneStrategy::BlobLimit(1 << 20).to_string(), "blob-limit=1MB");
+
+        let invalid = vec!["", "shallow", "full=1", "depth", "depth=0", "depth=-1", "blob-limit=", "blob-limit=lots", "single-branch=main"];
+        for s in invalid {
+            assert!(s.parse::<CloneStrategy>().is_err(), "strategy {} should be invalid", s);
+        }
+    }
+
+    #[test]
+    fn test_is_transient() {
+        let transient = vec![
+            "Timed out after 60 seconds",
+            "fatal: unable to access 'https://github.com/jsdw/git-backup.git/': Connection reset by peer",
+            "ssh: connect to host github.com port 22: Connection timed out",
+            "fatal: unable to access 'https://github.com/jsdw/git-backup.git/': The requested URL returned error: 503",
+            "Network error: unexpected http status code: 502",
+            "fatal: the remote end hung up unexpectedly",
+            "error: RPC failed; curl 56 GnuTLS recv error (-54): Error in the pull function.",
+            "fatal: early EOF",
+            "fatal: unable to access 'https://gitlab.com/': Could not resolve host: gitlab.com",
+        ];
+        for error in transient {
+            assert!(is_transient(error), "error should be transient: {}", error);
+        }
+
+        let permanent = vec![
+            "fatal: Authentication failed for 'https://github.com/jsdw/git-backup.git/'",
+            "fatal: repository 'https://github.com/jsdw/nope.git/' not found",
+            "fatal: unable to access 'https://github.com/jsdw/git-backup.git/': The requested URL returned error: 403",
+            "Host key verification failed",
+            "Refusing to sync from 'ext::sh': remote helpers are not supported",
+            "Destination '/backups/foo.git' already exists and is not an empty folder",
+        ];
+        for error in permanent {
+            assert!(!is_transient(error), "error should not be transient: {}", error);
+        }
+    }
+
+    #[test]
+    fn test_preserve_history() {
+        let dir = std::env::temp_dir().join(format!("git-backup-test-history-{}", std::process::id()));
+        let upstream = dir.join("upstream");
+        let backup = dir.join("backup");
+        std::fs::create_dir_all(&upstream).unwrap();
+
+        run_git(&upstream, &["init", "--quiet"]);
+        run_git(&upstream, &["commit", "--quiet", "--allow-empty", "-m", "first"]);
+        let first = run_git(&upstream, &["rev-parse", "HEAD"]);
+        for branch in &["forwards", "rewritten", "deleted"] {
+            run_git(&upstream, &["branch", branch]);
+        }
+        run_git(&upstream, &["tag", "v1"]);
+
+        let url = format!("file://{}", upstream.to_string_lossy());
+        let sync = || sync_repository(Opts { repo_url: &url, ..test_opts(&backup) }).unwrap();
+        assert_eq!(sync(), SyncOutcome { fetched: true, ..SyncOutcome::default() });
+        // Nothing has changed upstream, so there's nothing to fetch:
+        assert_eq!(sync(), SyncOutcome::default());
+
+        // Move one branch forwards, and rewrite, retag or delete the others:
+        run_git(&upstream, &["checkout", "--quiet", "forwards"]);
+        run_git(&upstream, &["commit", "--quiet", "--allow-empty", "-m", "second"]);
+        run_git(&upstream, &["checkout", "--quiet", "rewritten"]);
+        run_git(&upstream, &["commit", "--quiet", "--allow-empty", "--amend", "-m", "rewritten"]);
+        run_git(&upstream, &["tag", "--force", "v1"]);
+        run_git(&upstream, &["branch", "--delete", "--force", "deleted"]);
+
+        let outcome = sync();
+        assert!(outcome.fetched);
+        let preserved: Vec<_> = outcome.preserved.iter().map(|r| (&*r.name, r.deleted)).collect();
+        assert_eq!(preserved, vec![
+            ("refs/heads/deleted", true),
+            ("refs/heads/rewritten", false),
+            ("refs/tags/v1", false),
+        ]);
+        for r in &outcome.preserved {
+            assert!(r.preserved_as.starts_with("refs/git-backup/"));
+            assert!(r.preserved_as.ends_with(r.name.trim_start_matches("refs/")));
+            assert_eq!(run_git(&backup, &["rev-parse", &r.preserved_as]), first);
+        }
+        assert_ne!(run_git(&backup, &["rev-parse", "refs/heads/rewritten"]), first);
+
+        // Nothing else has changed, and the old history is still kept:
+        assert_eq!(sync(), SyncOutcome::default());
+        for r in &outcome.preserved {
+            assert_eq!(run_git(&backup, &["rev-parse", &r.preserved_as]), first);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_history_is_kept_when_fetching_fails() {
+        let dir = std::env::temp_dir().join(format!("git-backup-test-history-failed-{}", std::process::id()));
+        let upstream = dir.join("upstream");
+        let backup = dir.join("backup");
+        std::fs::create_dir_all(&upstream).unwrap();
+
+        run_git(&upstream, &["init", "--quiet"]);
+        run_git(&upstream, &["commit", "--quiet", "--allow-empty", "-m", "first"]);
+        let first = run_git(&upstream, &["rev-parse", "HEAD"]);
+        let default_branch = run_git(&upstream, &["symbolic-ref", "--short", "HEAD"]);
+        run_git(&upstream, &["branch", "deleted"]);
+
+        let url = format!("file://{}", upstream.to_string_lossy());
+        let sync = || sync_repository(Opts { repo_url: &url, ..test_opts(&backup) });
+        assert!(sync().unwrap().fetched);
+
+        // Rewrite the default branch and delete the other, but lose the new commit
+        // so that the remote still lists it while fetching it fails:
+        run_git(&upstream, &["commit", "--quiet", "--allow-empty", "--amend", "-m", "rewritten"]);
+        run_git(&upstream, &["branch", "--delete", "--force", "deleted"]);
+        let rewritten = run_git(&upstream, &["rev-parse", "HEAD"]);
+        std::fs::remove_file(upstream.join(".git/objects").join(&rewritten[..2]).join(&rewritten[2..])).unwrap();
+        assert!(sync().is_err());
+
+        let kept = run_git(&backup, &["for-each-ref", "--format=%(objectname) %(refname)", "refs/git-backup/"]);
+        let kept: Vec<_> = kept.lines().map(|line| line.split_once(' ').unwrap()).collect();
+        assert_eq!(kept.len(), 2);
+        for (id, name) in kept {
+            assert_eq!(id, first);
+            assert!(name.ends_with("/heads/deleted") || name.ends_with(&format!("/heads/{}", default_branch)), "unexpected ref {}", name);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unfinished_clones() {
+        let dir = std::env::temp_dir().join(format!("git-backup-test-unfinished-{}", std::process::id()));
+        let upstream = dir.join("upstream");
+        let backup = dir.join("backup.git");
+        let incomplete = dir.join("backup.git.incomplete");
+        std::fs::create_dir_all(&upstream).unwrap();
+        run_git(&upstream, &["init", "--quiet"]);
+        run_git(&upstream, &["commit", "--quiet", "--allow-empty", "-m", "first"]);
+
+        let sync = |url: &str| sync_repository(Opts { repo_url: url, ..test_opts(&backup) });
+
+        // A clone that fails leaves nothing behind:
+        assert!(sync("file:///nonexistent/repo").is_err());
+        assert!(!backup.exists());
+        assert!(!incomplete.exists());
+
+        // Neither does one that was interrupted, or one that an older version never finished:
+        std::fs::create_dir_all(incomplete.join("objects")).unwrap();
+        std::fs::create_dir_all(&backup).unwrap();
+        std::fs::write(backup.join("HEAD"), "ref: refs/heads/master\n").unwrap();
+        let url = format!("file://{}", upstream.to_string_lossy());
+        let outcome = sync(&url).unwrap();
+        assert!(outcome.recloned);
+        assert!(outcome.fetched);
+        assert!(!incomplete.exists());
+        assert_eq!(run_git(&backup, &["rev-parse", "HEAD"]), run_git(&upstream, &["rev-parse", "HEAD"]));
+
+        // Finished backups are left alone:
+        let outcome = sync(&url).unwrap();
+        assert!(!outcome.recloned);
+        assert!(!outcome.fetched);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = std::env::temp_dir().join(format!("git-backup-test-verify-{}", std::process::id()));
+        let upstream = dir.join("upstream");
+        let backup = dir.join("backup.git");
+        std::fs::create_dir_all(&upstream).unwrap();
+        run_git(&upstream, &["init", "--quiet"]);
+        std::fs::write(upstream.join("file"), "contents").unwrap();
+        run_git(&upstream, &["add", "file"]);
+        run_git(&upstream, &["commit", "--quiet", "-m", "first"]);
+        run_git(&upstream, &["tag", "-a", "-m", "v1", "v1"]);
+
+        let url = format!("file://{}", upstream.to_string_lossy());
+        let outcome = sync_repository(Opts { repo_url: &url, verify: true, ..test_opts(&backup) }).unwrap();
+        assert_eq!(outcome.verification, Some(Verification::Ok));
+        assert!(is_backup(&backup));
+
+        // Losing the objects that the refs point to is noticed:
+        std::fs::remove_dir_all(backup.join("objects")).unwrap();
+        std::fs::create_dir_all(backup.join("objects").join("pack")).unwrap();
+        assert!(matches!(verify_repository(&backup), Verification::Corrupt { .. }));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_repair() {
+        let dir = std::env::temp_dir().join(format!("git-backup-test-repair-{}", std::process::id()));
+        let upstream = dir.join("upstream");
+        let backup = dir.join("backup.git");
+        let quarantine = dir.join("quarantine");
+        std::fs::create_dir_all(&upstream).unwrap();
+        run_git(&upstream, &["init", "--quiet"]);
+        run_git(&upstream, &["commit", "--quiet", "--allow-empty", "-m", "first"]);
+        let head = run_git(&upstream, &["rev-parse", "HEAD"]);
+
+        let url = format!("file://{}", upstream.to_string_lossy());
+        let sync = || sync_repository(Opts { repo_url: &url, verify: true, quarantine: Some(&quarantine), ..test_opts(&backup) }).unwrap();
+        assert_eq!(sync().repair, None);
+
+        // History that's only in the backup, one part of which is then damaged along with
+        // the history that's upstream. Fetching this little leaves the objects loose, but
+        // the history from upstream may have been fetched in a pack:
+        let scratch = dir.join("scratch");
+        std::fs::create_dir_all(&scratch).unwrap();
+        run_git(&scratch, &["init", "--quiet"]);
+        for name in &["kept", "damaged"] {
+            std::fs::write(scratch.join(name), name).unwrap();
+            run_git(&scratch, &["add", name]);
+            run_git(&scratch, &["commit", "--quiet", "-m", name]);
+            run_git(&scratch, &["branch", name]);
+        }
+        let kept = run_git(&scratch, &["rev-parse", "kept"]);
+        let damaged = run_git(&scratch, &["rev-parse", "damaged"]);
+        run_git(&backup, &["fetch", "--quiet", &scratch.to_string_lossy(),
+            "kept:refs/git-backup/20191001T120000Z/heads/kept",
+            "damaged:refs/git-backup/20191001T120000Z/heads/damaged"]);
+        for id in &[&head, &damaged] {
+            let _ = std::fs::remove_file(backup.join("objects").join(&id[..2]).join(&id[2..]));
+        }
+        for entry in std::fs::read_dir(backup.join("objects").join("pack")).unwrap() {
+            std::fs::remove_file(entry.unwrap().path()).unwrap();
+        }
+
+        let outcome = sync();
+        let repair = outcome.repair.unwrap();
+        assert_eq!(outcome.verification, Some(Verification::Ok));
+        assert_eq!(repair.salvaged, vec!["refs/git-backup/20191001T120000Z/heads/kept"]);
+        assert_eq!(repair.lost, vec!["refs/git-backup/20191001T120000Z/heads/damaged"]);
+        assert!(repair.quarantined_as.starts_with(&quarantine));
+        assert!(repair.quarantined_as.join("HEAD").is_file());
+        assert_eq!(run_git(&backup, &["rev-parse", "refs/git-backup/20191001T120000Z/heads/kept"]), kept);
+        assert_eq!(run_git(&backup, &["rev-parse", "HEAD"]), head);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_clone_strategy_is_kept() {
+        let dir = std::env::temp_dir().join(format!("git-backup-test-strategy-{}", std::process::id()));
+        let upstream = dir.join("upstream");
+        let backup = dir.join("backup");
+        std::fs::create_dir_all(&upstream).unwrap();
+
+        run_git(&upstream, &["init", "--quiet"]);
+        for message in &["first", "second", "third"] {
+            run_git(&upstream, &["commit", "--quiet", "--allow-empty", "-m", message]);
+        }
+
+        let url = format!("file://{}", upstream.to_string_lossy());
+        let sync = |strategy| sync_repository(Opts { repo_url: &url, strategy, force_fetch: true, ..test_opts(&backup) }).unwrap();
+        assert_eq!(sync(CloneStrategy::Depth(1)).strategy, CloneStrategy::Depth(1));
+        // libgit2 ignores the depth when fetching from a local repository:
+        if cfg!(not(feature = "native-git")) {
+            assert_eq!(run_git(&backup, &["rev-list", "--count", "HEAD"]), "1");
+        }
+
+        // Asking for something else later doesn't change how the backup is cloned,
+        // and moving a branch forwards isn't mistaken for rewriting it:
+        run_git(&upstream, &["commit", "--quiet", "--allow-empty", "-m", "fourth"]);
+        let outcome = sync(CloneStrategy::Full);
+        assert_eq!(outcome.strategy, CloneStrategy::Depth(1));
+        assert!(outcome.preserved.is_empty());
+        assert_eq!(run_git(&backup, &["config", "git-backup.strategy"]), "depth=1");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
@@ -0,0 +1,429 @@
+use std::time::{ Duration, Instant };
+use std::cell::Cell;
+use std::rc::Rc;
+use std::path::Path;
+use std::collections::HashSet;
+use regex::Regex;
+use lazy_static::lazy_static;
+use git2::{ Repository, RemoteCallbacks, FetchOptions, FetchPrune, AutotagOption, Cred, CredentialType,
+            CertificateCheckStatus, Direction, ConfigLevel, ErrorClass, ErrorCode, Oid, ObjectType, Odb };
+use crate::error::Error;
+use super::{ Opts, Progress, Refs, RemoteRefs, CloneStrategy, OLD_CREDENTIAL_HELPER_RE, credential_target };
+use super::known_hosts::{ self, HostKeyStatus };
+
+/// How often to report on the progress of a transfer.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+pub fn init(opts: &Opts) -> Result<(),Error> {
+    Repository::init_bare(opts.destination).map_err(to_error)?;
+    Ok(())
+}
+
+pub fn configure(opts: &Opts) -> Result<(),Error> {
+    let repo = Repository::open_bare(opts.destination).map_err(to_error)?;
+    remove_old_credential_helper(&repo)?;
+    // Record where the repository came from, as 'git clone' would:
+    repo.config()
+        .and_then(|mut config| config.set_str("remote.origin.url", opts.repo_url))
+        .map_err(to_error)
+}
+
+/// The equivalent of `git ls-remote --symref origin`.
+pub fn ls_remote(opts: &Opts) -> Result<RemoteRefs,Error> {
+    let repo = Repository::open_bare(opts.destination).map_err(to_error)?;
+    let mut remote = repo.remote_anonymous(opts.repo_url).map_err(to_error)?;
+    let stalled = Cell::new(false);
+    let connection = remote.connect_auth(Direction::Fetch, Some(callbacks(opts, &stalled)?), None)
+        .map_err(|e| to_timeout_error(opts, &stalled).unwrap_or_else(|| to_error(e)))?;
+
+    // This errors if the remote has no HEAD (eg it's empty):
+    let head = connection.default_branch().ok()
+        .and_then(|branch| branch.as_str().and_then(|b| b.strip_prefix("refs/heads/")).map(|b| b.to_owned()));
+    let refs = connection.list().map_err(to_error)?
+        .iter()
+        // Annotated tags are also listed with '^{}' on the end,
+        // alongside the commit that they point to:
+        .filter(|r| r.name().starts_with("refs/") && !r.name().ends_with("^{}"))
+        .map(|r| (r.name().to_owned(), r.oid().to_string()))
+        .collect();
+
+    Ok(RemoteRefs { head, refs })
+}
+
+/// The equivalent of `git fetch --prune origin <refspecs>`.
+pub fn fetch(opts: &Opts, refspecs: &[String], strategy: CloneStrategy) -> Result<(),Error> {
+    let repo = Repository::open_bare(opts.destination).map_err(to_error)?;
+
+    let stalled = Cell::new(false);
+    let mut fetch_opts = FetchOptions::new();
+    fetch_opts.remote_callbacks(callbacks(opts, &stalled)?);
+    fetch_opts.prune(FetchPrune::On);
+    // Tags are covered by the refspecs, if we want them:
+    fetch_opts.download_tags(AutotagOption::None);
+    match strategy {
+        // libgit2 has no support for partial clones:
+        CloneStrategy::BlobLimit(_) => return Err(err!("The '{}' clone strategy is not supported when built with native-git", strategy)),
+        CloneStrategy::Depth(depth) => { fetch_opts.depth(depth.min(i32::MAX as u32) as i32); },
+        CloneStrategy::Full | CloneStrategy::SingleBranch => {}
+    }
+
+    // libgit2 doesn't understand negative refspecs, so put back the refs
+    // that they would have kept out of the prune ourselves, whether or not
+    // the fetch works:
+    let (excluded, refspecs): (Vec<&String>, Vec<&String>) = refspecs.iter().partition(|r| r.starts_with('^'));
+    let is_excluded = |name: &str| excluded.iter().any(|r| name.starts_with(r[1..].trim_end_matches('*')));
+    let keep: Refs = refs(opts)?.into_iter().filter(|(name, _)| is_excluded(name)).collect();
+
+    let mut remote = repo.remote_anonymous(opts.repo_url).map_err(to_error)?;
+    let fetched = remote.fetch(&refspecs, Some(&mut fetch_opts), None)
+        .map_err(|e| to_timeout_error(opts, &stalled).unwrap_or_else(|| to_error(e)));
+    for (name, id) in &keep {
+        if repo.find_reference(name).is_err() {
+            set_ref(opts, name, id)?;
+        }
+    }
+    fetched
+}
+
+pub fn get_config(opts: &Opts, key: &str) -> Result<Option<String>,Error> {
+    let repo = Repository::open_bare(opts.destination).map_err(to_error)?;
+    let config = repo.config().and_then(|config| config.open_level(ConfigLevel::Local)).map_err(to_error)?;
+    match config.get_string(key) {
+        Ok(value) => Ok(Some(value)),
+        Err(ref e) if e.code() == ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(to_error(e))
+    }
+}
+
+pub fn set_config(opts: &Opts, key: &str, value: &str) -> Result<(),Error> {
+    let repo = Repository::open_bare(opts.destination).map_err(to_error)?;
+    repo.config()
+        .and_then(|mut config| config.set_str(key, value))
+        .map_err(to_error)
+}
+
+pub fn set_head(opts: &Opts, branch: &str) -> Result<(),Error> {
+    let repo = Repository::open_bare(opts.destination).map_err(to_error)?;
+    repo.set_head(&format!("refs/heads/{}", branch)).map_err(to_error)?;
+    Ok(())
+}
+
+pub fn refs(opts: &Opts) -> Result<Refs,Error> {
+    let repo = Repository::open_bare(opts.destination).map_err(to_error)?;
+    let mut refs = Refs::new();
+    for reference in repo.references().map_err(to_error)? {
+        let reference = reference.map_err(to_error)?;
+        // Symbolic refs have no target of their own:
+        if let (Some(name), Some(id)) = (reference.name(), reference.target()) {
+            refs.insert(name.to_owned(), id.to_string());
+        }
+    }
+    Ok(refs)
+}
+
+/// The contents of the `.gitmodules` file in each ref, leaving out duplicates.
+pub fn gitmodules(opts: &Opts) -> Result<Vec<String>,Error> {
+    let repo = Repository::open_bare(opts.destination).map_err(to_error)?;
+    let mut seen = HashSet::new();
+    let mut files = vec![];
+    for reference in repo.references().map_err(to_error)? {
+        let reference = reference.map_err(to_error)?;
+        // Refs that don't point to a commit (or have no .gitmodules) are skipped:
+        let entry = match reference.peel_to_tree().and_then(|tree| tree.get_path(Path::new(".gitmodules"))) {
+            Ok(entry) => entry,
+            Err(_) => continue
+        };
+        if !seen.insert(entry.id()) {
+            continue
+        }
+        if let Ok(blob) = repo.find_blob(entry.id()) {
+            files.push(String::from_utf8_lossy(blob.content()).into_owned());
+        }
+    }
+    Ok(files)
+}
+
+pub fn is_ancestor(opts: &Opts, ancestor: &str, descendant: &str) -> bool {
+    let check = || -> Result<bool,git2::Error> {
+        let repo = Repository::open_bare(opts.destination)?;
+        let ancestor = repo.find_object(Oid::from_str(ancestor)?, None)?.peel_to_commit()?.id();
+        let descendant = repo.find_object(Oid::from_str(descendant)?, None)?.peel_to_commit()?.id();
+        Ok(ancestor == descendant || repo.graph_descendant_of(descendant, ancestor)?)
+    };
+    check().unwrap_or(false)
+}
+
+pub fn set_ref(opts: &Opts, name: &str, id: &str) -> Result<(),Error> {
+    let repo = Repository::open_bare(opts.destination).map_err(to_error)?;
+    let id = Oid::from_str(id).map_err(to_error)?;
+    repo.reference(name, id, true, "git-backup: preserve history").map_err(to_error)?;
+    Ok(())
+}
+
+pub fn delete_ref(opts: &Opts, name: &str) -> Result<(),Error> {
+    let repo = Repository::open_bare(opts.destination).map_err(to_error)?;
+    repo.find_reference(name).and_then(|mut r| r.delete()).map_err(to_error)?;
+    Ok(())
+}
+
+pub fn has_object(opts: &Opts, id: &str) -> bool {
+    let check = || -> Result<bool,git2::Error> {
+        let repo = Repository::open_bare(opts.destination)?;
+        let exists = repo.odb()?.exists(Oid::from_str(id)?);
+        Ok(exists)
+    };
+    check().unwrap_or(false)
+}
+
+/// Copy an object from another repository on disk, along with everything it
+/// refers to, without creating any refs of our own. libgit2 won't fetch from
+/// a repository that's missing objects we already have (as a damaged one
+/// might be), so the objects are copied across directly instead.
+pub fn fetch_object_from(opts: &Opts, path: &Path, id: &str) -> Result<(),Error> {
+    let source = Repository::open_bare(path).map_err(to_error)?;
+    let source_odb = source.odb().map_err(to_error)?;
+    let repo = Repository::open_bare(opts.destination).map_err(to_error)?;
+    let odb = repo.odb().map_err(to_error)?;
+
+    let mut pending = vec![Oid::from_str(id).map_err(to_error)?];
+    let mut seen = HashSet::new();
+    let mut objects = vec![];
+    while let Some(id) = pending.pop() {
+        if !seen.insert(id) || odb.exists(id) {
+            continue
+        }
+        let object = source_odb.read(id)
+            .map_err(|e| err!("object {} is missing or corrupt: {}", id, e.message()))?;
+        match object.kind() {
+            ObjectType::Commit => {
+                let commit = source.find_commit(id).map_err(to_error)?;
+                pending.push(commit.tree_id());
+                pending.extend(commit.parent_ids());
+            },
+            ObjectType::Tree => {
+                let tree = source.find_tree(id).map_err(to_error)?;
+                // Submodules point to commits in other repositories:
+                pending.extend(tree.iter().filter(|e| e.kind() != Some(ObjectType::Commit)).map(|e| e.id()));
+            },
+            ObjectType::Tag => pending.push(source.find_tag(id).map_err(to_error)?.target_id()),
+            _ => {}
+        }
+        objects.push(object);
+    }
+
+    // Nothing is written until we know that all of the history is intact:
+    for object in objects {
+        odb.write(object.kind(), object.data()).map_err(to_error)?;
+    }
+    Ok(())
+}
+
+/// libgit2 has no equivalent of `git fsck`, so read every object that can be
+/// reached from a ref instead. Reading an object checks that it matches its ID.
+pub fn fsck(path: &Path) -> Result<(),Error> {
+    let repo = Repository::open_bare(path).map_err(to_error)?;
+    let odb = repo.odb().map_err(to_error)?;
+    let mut checked = HashSet::new();
+    let mut revwalk = repo.revwalk().map_err(to_error)?;
+
+    for reference in repo.references().map_err(to_error)? {
+        let reference = reference.map_err(to_error)?;
+        let name = reference.name().unwrap_or("").to_owned();
+        let mut id = match reference.target() {
+            Some(id) => id,
+            None => continue
+        };
+        // Follow tags to whatever they point to:
+        loop {
+            let object = read_object(&odb, id, &mut checked)
+                .map_err(|e| err!("{} points to an object that is missing or corrupt: {}", name, e))?;
+            match object {
+                Some(ObjectType::Tag) => id = repo.find_tag(id).map_err(to_error)?.target_id(),
+                Some(ObjectType::Commit) => { revwalk.push(id).map_err(to_error)?; break },
+                Some(ObjectType::Tree) => { check_tree(&repo, &odb, id, &mut checked)?; break },
+                _ => break
+            }
+        }
+    }
+
+    for id in revwalk {
+        let id = id.map_err(|e| err!("Could not walk history: {}", e.message()))?;
+        read_object(&odb, id, &mut checked).map_err(|e| err!("commit {} is missing or corrupt: {}", id, e))?;
+        let commit = repo.find_commit(id).map_err(to_error)?;
+        check_tree(&repo, &odb, commit.tree_id(), &mut checked)?;
+    }
+    Ok(())
+}
+
+/// Read every object in a tree (and in the trees inside it).
+fn check_tree(repo: &Repository, odb: &Odb, id: Oid, checked: &mut HashSet<Oid>) -> Result<(),Error> {
+    if read_object(odb, id, checked).map_err(|e| err!("tree {} is missing or corrupt: {}", id, e))?.is_none() {
+        return Ok(())
+    }
+    let tree = repo.find_tree(id).map_err(to_error)?;
+    for entry in tree.iter() {
+        match entry.kind() {
+            Some(ObjectType::Tree) => check_tree(repo, odb, entry.id(), checked)?,
+            // Submodules point to commits in other repositories:
+            Some(ObjectType::Commit) => {},
+            _ => { read_object(odb, entry.id(), checked).map_err(|e| err!("blob {} is missing or corrupt: {}", entry.id(), e))?; }
+        }
+    }
+    Ok(())
+}
+
+/// Read an object (checking it against its ID) and hand back its type,
+/// unless we've already checked it.
+fn read_object(odb: &Odb, id: Oid, checked: &mut HashSet<Oid>) -> Result<Option<ObjectType>,String> {
+    if !checked.insert(id) {
+        return Ok(None)
+    }
+    odb.read(id)
+        .map(|object| Some(object.kind()))
+        .map_err(|e| e.message().to_owned())
+}
+
+/// Remove the credential helper that older versions of this tool saved
+/// into the config of each backup, if it's there.
+fn remove_old_credential_helper(repo: &Repository) -> Result<(),Error> {
+    let mut config = repo.config()
+        .and_then(|config| config.open_level(ConfigLevel::Local))
+        .map_err(to_error)?;
+    match config.remove_multivar("credential.helper", OLD_CREDENTIAL_HELPER_RE) {
+        Err(ref e) if e.code() == ErrorCode::NotFound => Ok(()),
+        res => res.map_err(to_error)
+    }
+}
+
+/// The callbacks to hand to libgit2 when talking to the remote. If a transfer
+/// stalls for longer than the timeout, it's stopped and `stalled` is set.
+fn callbacks<'a>(opts: &'a Opts, stalled: &'a Cell<bool>) -> Result<RemoteCallbacks<'a>,Error> {
+    let mut callbacks = RemoteCallbacks::new();
+
+    // libgit2 keeps asking for credentials until they're accepted,
+    // so only hand them over once, and only to the remote itself:
+    let mut tried_credentials = false;
+    let target = credential_target(opts.repo_url);
+    callbacks.credentials(move |url, username, allowed| {
+        let username = username.unwrap_or("git");
+        if opts.ssh.is_some() && allowed == CredentialType::USERNAME {
+            return Cred::username(username)
+        }
+        if tried_credentials {
+            let message = if opts.ssh.is_some() { "the SSH key was not accepted" } else { "the username and token were not accepted" };
+            return Err(git2::Error::new(ErrorCode::Auth, ErrorClass::Callback, message))
+        }
+        tried_credentials = true;
+        match opts.ssh {
+            Some(ssh) if allowed.contains(CredentialType::SSH_KEY) => Cred::ssh_key(username, None, &ssh.key, None),
+            None if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) && target.is_some() && credential_target(url) == target => {
+                Cred::userpass_plaintext(opts.username, opts.password)
+            },
+            _ => Err(git2::Error::new(ErrorCode::Auth, ErrorClass::Callback, "the remote asked for credentials that we don't have"))
+        }
+    });
+
+    // libgit2 only checks host keys against ~/.ssh/known_hosts, so
+    // check them against any other file that we've been given ourselves:
+    if let Some(path) = opts.ssh.and_then(|ssh| ssh.known_hosts.as_ref()) {
+        let known_hosts = std::fs::read_to_string(path)
+            .map_err(|e| err!("Could not read known hosts file '{}': {}", path.to_string_lossy(), e))?;
+        let port = ssh_port(opts.repo_url);
+        callbacks.certificate_check(move |cert, host| {
+            let key = match cert.as_hostkey().and_then(|k| k.hostkey()) {
+                Some(key) => key,
+                None => return Ok(CertificateCheckStatus::CertificatePassthrough)
+            };
+            let message = match known_hosts::check(&known_hosts, host, port, key) {
+                HostKeyStatus::Known => return Ok(CertificateCheckStatus::CertificateOk),
+                HostKeyStatus::Changed => format!("the key for '{}' does not match the one in the known hosts file", host),
+                HostKeyStatus::Unknown => format!("'{}' is not in the known hosts file", host)
+            };
+            Err(git2::Error::new(ErrorCode::Certificate, ErrorClass::Ssh, &message))
+        });
+    }
+
+    // libgit2 has no timeouts of its own, but we can stop it whenever it
+    // tells us how things are going, if it's been too long since it last did:
+    let last_progress = Rc::new(Cell::new(Instant::now()));
+    let in_time = move || {
+        let in_time = opts.timeout.map(|timeout| last_progress.get().elapsed() < timeout).unwrap_or(true);
+        last_progress.set(Instant::now());
+        stalled.set(stalled.get() || !in_time);
+        in_time
+    };
+    let sideband_in_time = in_time.clone();
+    callbacks.sideband_progress(move |_| sideband_in_time());
+
+    // Only report on transfers that take a while, so that we don't
+    // drown out everything else:
+    let mut last_report = Instant::now();
+    callbacks.transfer_progress(move |stats| {
+        if !in_time() {
+            return false
+        }
+        if last_report.elapsed() >= PROGRESS_INTERVAL && stats.received_objects() < stats.total_objects() {
+            last_report = Instant::now();
+            (opts.on_progress)(&Progress {
+                received_objects: stats.received_objects(),
+                total_objects: stats.total_objects(),
+                received_bytes: stats.received_bytes()
+            });
+        }
+        true
+    });
+
+    Ok(callbacks)
+}
+
+/// The port that an SSH URL connects to.
+fn ssh_port(url: &str) -> u16 {
+    lazy_static! {
+        static ref PORT_RE: Regex = Regex::new("^[a-zA-Z+]+://(?:[^@/]*@)?[^/:]+:([0-9]+)/").unwrap();
+    }
+    PORT_RE.captures(url)
+        .and_then(|caps| caps[1].parse().ok())
+        .unwrap_or(22)
+}
+
+/// If a transfer stalled, libgit2 fails because we told it to stop, so say why.
+fn to_timeout_error(opts: &Opts, stalled: &Cell<bool>) -> Option<Error> {
+    let timeout = opts.timeout?;
+    if stalled.get() {
+        Some(err!("Timed out after {} seconds without any progress", timeout.as_secs()))
+    } else {
+        None
+    }
+}
+
+/// Say what kind of error libgit2 gave back, if we can.
+fn to_error(e: git2::Error) -> Error {
+    let message = e.message().trim();
+    match (e.code(), e.class()) {
+        (ErrorCode::Auth, _) => err!("Authentication failed: {}", message),
+        (ErrorCode::Certificate, ErrorClass::Ssh) => err!("Host key verification failed: {}", message),
+        (ErrorCode::Certificate, _) => err!("Certificate error: {}", message),
+        (_, ErrorClass::Net) | (_, ErrorClass::Http) | (_, ErrorClass::Ssl) | (_, ErrorClass::Ssh) => err!("Network error: {}", message),
+        _ => err!("{}", message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_ssh_port() {
+        let ports = vec![
+            ("git@github.com:jsdw/git-backup.git", 22),
+            ("ssh://git@github.com/jsdw/git-backup.git", 22),
+            ("ssh://git@git.example.com:2222/jsdw/git-backup.git", 2222),
+            ("ssh://git.example.com:2222/jsdw/git-backup.git", 2222),
+        ];
+        for (url, port) in ports {
+            assert_eq!(ssh_port(url), port, "url {} has the wrong port", url);
+        }
+    }
+
+}
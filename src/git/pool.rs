@@ -0,0 +1,220 @@
+use std::path::{ Path, PathBuf };
+use std::collections::hash_map::RandomState;
+use std::hash::{ BuildHasher, Hash, Hasher };
+use std::time::SystemTime;
+use serde::Serialize;
+use crate::error::Error;
+use super::Opts;
+use super::command::{ git, git_in, run, run_with_input };
+use super::lfs::dir_size;
+
+/// The refs of each backup that shares a pool are kept in it under here,
+/// so that the objects they need are never removed from the pool.
+const MEMBERS_PREFIX: &str = "refs/members/";
+
+/// The config key that records which refs in its pool belong to a backup.
+const MEMBER_KEY: &str = "git-backup.poolMember";
+
+#[derive(Debug,Clone,PartialEq,Eq,Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum SharingOutcome {
+    Shared {
+        /// The pool repository that the backup shares objects through
+        pool: PathBuf,
+        /// The size (in bytes) of the objects kept in the backup itself beforehand
+        size_before: u64,
+        /// The size (in bytes) of the objects kept in the backup itself afterwards
+        size_after: u64
+    },
+    Failed { error: String }
+}
+
+impl SharingOutcome {
+    /// How much space (in bytes) the backup no longer needs for itself.
+    pub fn saved(&self) -> u64 {
+        match self {
+            SharingOutcome::Shared { size_before, size_after, .. } => size_before.saturating_sub(*size_after),
+            SharingOutcome::Failed { .. } => 0
+        }
+    }
+}
+
+/// Share the objects in a backup with any related backups, through a pool
+/// repository in the folder given. Backups are related if their default
+/// branches have the same root commit, which is the case for forks, and for
+/// the same repository backed up from different places. Nothing needs doing
+/// to a backup that already shares a pool if we haven't fetched anything into it.
+pub fn share(opts: &Opts, pools: &Path, fetched: bool) -> Option<SharingOutcome> {
+    let pool = match pool_of(opts.destination) {
+        Some(_) if !fetched => return None,
+        Some(pool) => pool,
+        None => match find_pool(opts, pools) {
+            Ok(Some(pool)) => pool,
+            Ok(None) => return None,
+            Err(e) => return Some(SharingOutcome::Failed { error: e.to_string() })
+        }
+    };
+    let objects = opts.destination.join("objects");
+    let size_before = dir_size(&objects);
+    match join_pool(opts, &pool) {
+        Ok(()) => Some(SharingOutcome::Shared { pool, size_before, size_after: dir_size(&objects) }),
+        Err(e) => Some(SharingOutcome::Failed { error: e.to_string() })
+    }
+}
+
+/// Remove a backup's refs from the pool that it shares objects through (if
+/// any), before the backup itself is removed. Anything that other backups
+/// need is still referred to by their own refs in the pool, so the objects
+/// that only this backup needed can then be cleaned up, and a pool that's
+/// no longer shared by anything is removed altogether.
+pub fn leave_pool(path: &Path) -> Result<(),Error> {
+    let pool = match pool_of(path) {
+        Some(pool) => pool,
+        None => return Ok(())
+    };
+    let member = member_prefix(&member_id(path)?);
+    let deletes = run(git_in(&pool).args(["for-each-ref", "--format=delete %(refname)"]).arg(&member))?;
+    if !deletes.trim().is_empty() {
+        run_with_input(git_in(&pool).args(["update-ref", "--stdin"]), deletes)?;
+    }
+
+    let members = run(git_in(&pool).args(["for-each-ref", "--count=1", MEMBERS_PREFIX]))?;
+    if members.trim().is_empty() {
+        std::fs::remove_dir_all(&pool)?;
+    } else {
+        // Unreachable objects are kept for a couple of weeks before they're
+        // pruned, so a backup that's busy joining the pool loses nothing:
+        run(git_in(&pool).args(["gc", "--quiet"]))?;
+    }
+    Ok(())
+}
+
+/// The pool repository that a backup borrows objects from, if it does.
+fn pool_of(path: &Path) -> Option<PathBuf> {
+    let alternates = std::fs::read_to_string(path.join("objects").join("info").join("alternates")).ok()?;
+    let objects = alternates.lines().map(|line| line.trim()).find(|line| !line.is_empty() && !line.starts_with('#'))?;
+    Path::new(objects).parent().map(|pool| pool.to_owned())
+}
+
+/// Work out which pool a backup belongs in, based on the root commit of its default
+/// branch. Backups without any commits have nothing worth sharing.
+fn find_pool(opts: &Opts, pools: &Path) -> Result<Option<PathBuf>,Error> {
+    let output = git(opts).args(["rev-list", "--max-parents=0", "HEAD"]).output()?;
+    if !output.status.success() {
+        return Ok(None)
+    }
+    // Histories that were merged together have several roots; any will do,
+    // so long as we pick the same one each time:
+    let roots = String::from_utf8_lossy(&output.stdout);
+    let root = match roots.lines().min() {
+        Some(root) => root.to_owned(),
+        None => return Ok(None)
+    };
+    std::fs::create_dir_all(pools)?;
+    Ok(Some(pools.canonicalize()?.join(format!("{}.git", root))))
+}
+
+fn join_pool(opts: &Opts, pool: &Path) -> Result<(),Error> {
+    if !pool.join("HEAD").is_file() {
+        std::fs::create_dir_all(pool)?;
+        run(git_in(pool).args(["init", "--bare", "--quiet"]))?;
+    }
+
+    // Copy the backup's objects into the pool, keeping its refs there too. Nothing
+    // is cleaned up afterwards, since the backups sharing the pool may be relying
+    // on objects that they haven't yet had the chance to copy into it:
+    let destination = opts.destination.canonicalize()?;
+    run(git_in(pool)
+        .args(["-c", "gc.auto=0", "-c", "maintenance.auto=false", "fetch", "--quiet", "--no-tags", "--prune"])
+        .arg(&destination)
+        .arg(format!("+refs/*:{}*", member_prefix(&member_id(&destination)?))))?;
+
+    // Git only gives up its own copies of objects that an alternate has
+    // packed, so pack up anything that was left loose:
+    run(git_in(pool).args(["-c", "gc.auto=0", "repack", "-d", "-q"]))?;
+
+    // Then have the backup borrow objects from the pool, and give up its own
+    // copies of them. Once a backup shares a pool, it keeps sharing that one:
+    if pool_of(opts.destination).is_none() {
+        let info = opts.destination.join("objects").join("info");
+        std::fs::create_dir_all(&info)?;
+        std::fs::write(info.join("alternates"), format!("{}\n", pool.join("objects").to_string_lossy()))?;
+    }
+    run(git(opts).args(["-c", "gc.auto=0", "repack", "-a", "-d", "-l", "-q"]))?;
+    Ok(())
+}
+
+/// The ID that a backup's refs are kept under in its pool. This is saved in the
+/// backup's config, so that moving or renaming the backup doesn't lose track of them.
+fn member_id(path: &Path) -> Result<String,Error> {
+    let output = git_in(path).args(["config", "--local", "--get", MEMBER_KEY]).output()?;
+    let saved = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    if output.status.success() && !saved.is_empty() {
+        return Ok(saved)
+    }
+
+    // The ID only needs to differ from those of the other backups in the pool:
+    let mut hasher = RandomState::new().build_hasher();
+    path.hash(&mut hasher);
+    SystemTime::now().hash(&mut hasher);
+    let id = format!("{:016x}", hasher.finish());
+    run(git_in(path).args(["config", "--local", MEMBER_KEY, &id]))?;
+    Ok(id)
+}
+
+/// Where in a pool the refs of the backup with the ID given are kept.
+fn member_prefix(id: &str) -> String {
+    format!("{}{}/", MEMBERS_PREFIX, id)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use super::super::test_fixtures::{ run_git, test_opts };
+
+    #[test]
+    fn test_share() {
+        let dir = std::env::temp_dir().join(format!("git-backup-test-pool-{}", std::process::id()));
+        let pools = dir.join("pools");
+        let original = dir.join("original.git");
+        let fork = dir.join("fork.git");
+        std::fs::create_dir_all(&original).unwrap();
+        run_git(&original, &["init", "--quiet", "--bare"]);
+        let tree = run_git(&original, &["write-tree"]);
+        let root = run_git(&original, &["commit-tree", &tree, "-m", "root"]);
+        run_git(&original, &["update-ref", "HEAD", &root]);
+        run_git(&dir, &["clone", "--quiet", "--bare", "original.git", "fork.git"]);
+        let head = run_git(&fork, &["commit-tree", &tree, "-p", &root, "-m", "forked"]);
+        run_git(&fork, &["update-ref", "HEAD", &head]);
+
+        // Both backups end up in the same pool, which holds everything they need:
+        let mut shared = vec![];
+        for backup in &[&original, &fork] {
+            match share(&test_opts(backup), &pools, true) {
+                Some(SharingOutcome::Shared { pool, .. }) => shared.push(pool),
+                outcome => panic!("backup should be shared, but got {:?}", outcome)
+            }
+            assert_eq!(run_git(backup, &["count-objects"]), "0 objects, 0 kilobytes");
+            run_git(backup, &["fsck", "--no-progress"]);
+        }
+        assert_eq!(shared[0], shared[1]);
+        assert_eq!(shared[0], pools.canonicalize().unwrap().join(format!("{}.git", root)));
+        assert_eq!(run_git(&shared[0], &["cat-file", "-t", &head]), "commit");
+
+        // Nothing needs doing until we fetch something again:
+        assert_eq!(share(&test_opts(&fork), &pools, false), None);
+
+        // Once a backup leaves the pool, its refs in the pool are gone:
+        leave_pool(&fork).unwrap();
+        let refs = run_git(&shared[0], &["for-each-ref", "--format=%(objectname)", MEMBERS_PREFIX]);
+        assert_eq!(refs, root);
+
+        // And once nothing shares the pool, it's removed:
+        leave_pool(&original).unwrap();
+        assert!(!shared[0].exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
@@ -0,0 +1,152 @@
+use std::path::{ Path, PathBuf };
+use regex::Regex;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use chrono::Utc;
+use crate::error::Error;
+use super::{ backend, Opts, Refs, SyncOutcome, CloneStrategy, PRESERVED_REFS_PREFIX };
+
+/// What we did about a backup that turned out to be corrupt.
+#[derive(Debug,Clone,PartialEq,Eq,Serialize)]
+pub struct Repair {
+    /// What was wrong with the backup
+    pub reason: String,
+    /// Where the damaged backup was moved to
+    pub quarantined_as: PathBuf,
+    /// Refs in the damaged backup whose history wasn't in the new clone,
+    /// but has been copied across from it
+    pub salvaged: Vec<String>,
+    /// Refs in the damaged backup whose history wasn't in the new clone,
+    /// and couldn't be copied across because it was damaged too
+    pub lost: Vec<String>,
+    /// Why nothing could be salvaged at all, if so
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>
+}
+
+/// Does an error look like it was caused by something in the backup being damaged?
+pub fn looks_corrupt(error: &str) -> bool {
+    lazy_static! {
+        static ref CORRUPT_RE: Regex = Regex::new(&[
+            "corrupt",
+            "bad object",
+            "missing (blob|tree|commit|tag|object)",
+            "invalid sha1 pointer",
+            "broken link",
+            "unable to read [0-9a-f]+",
+            "packfile .* (cannot be accessed|does not match index)",
+            "bad packed object",
+            "inflate",
+            "object not found",
+            "failed to read object",
+            "bad index file",
+            "unable to parse object",
+        ].join("|")).unwrap();
+    }
+    CORRUPT_RE.is_match(&error.to_lowercase())
+}
+
+/// Move a damaged backup into the quarantine folder and clone it again. Anything
+/// that was only in the damaged backup (like history that was rewritten upstream)
+/// is then copied across from it, if it's intact enough to do so. The refs that
+/// the backup had before it was synced are given, if they could be read.
+pub fn repair(opts: Opts, quarantine: &Path, reason: String, refs: Option<Refs>) -> Result<SyncOutcome,Error> {
+    let now = Utc::now();
+    let name = opts.destination.file_name().unwrap_or_default().to_string_lossy();
+    let quarantined = quarantine.join(format!("{}.{}", name, now.format("%Y%m%dT%H%M%SZ")));
+    std::fs::create_dir_all(quarantine)
+        .and_then(|_| std::fs::rename(opts.destination, &quarantined))
+        .map_err(|e| err!("Backup is corrupt ({}), and could not be moved to '{}': {}", reason.trim(), quarantined.to_string_lossy(), e))?;
+
+    // Put the damaged backup back if we can't replace it, so that
+    // we can try again (and salvage what we can from it) next time:
+    let mut outcome = match super::sync(opts) {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            let _ = std::fs::remove_dir_all(opts.destination);
+            let _ = std::fs::rename(&quarantined, opts.destination);
+            return Err(err!("Backup is corrupt ({}), and could not be cloned again: {}", reason.trim(), e))
+        }
+    };
+
+    let mut repair = Repair { reason, quarantined_as: quarantined, salvaged: vec![], lost: vec![], error: None };
+    let is_shallow = matches!(outcome.strategy, CloneStrategy::Depth(_));
+    if let Err(e) = salvage(&opts, refs, &mut repair, &mut outcome, is_shallow) {
+        repair.error = Some(e.to_string());
+    }
+    outcome.repair = Some(repair);
+    Ok(outcome)
+}
+
+/// Copy the history of any refs in the damaged backup that the new clone doesn't
+/// have into it, keeping them under refs/git-backup/ as we do for rewritten history.
+fn salvage(opts: &Opts, refs: Option<Refs>, repair: &mut Repair, outcome: &mut SyncOutcome, is_shallow: bool) -> Result<(),Error> {
+    let damaged = match refs {
+        Some(refs) => refs,
+        None => backend::refs(&Opts { destination: &repair.quarantined_as, ..*opts })
+            .map_err(|e| err!("Could not read the refs in the damaged backup: {}", e))?
+    };
+    let fresh = backend::refs(opts)?;
+
+    let mut intact = Refs::new();
+    for (name, id) in &damaged {
+        if fresh.get(name) == Some(id) {
+            continue
+        }
+        // Each ref is copied on its own, so that one with damaged
+        // history doesn't stop us copying the others:
+        let copied = backend::has_object(opts, id)
+            || (backend::fetch_object_from(opts, &repair.quarantined_as, id).is_ok() && backend::has_object(opts, id));
+        if copied {
+            intact.insert(name.to_owned(), id.to_owned());
+        } else {
+            repair.lost.push(name.to_owned());
+        }
+    }
+
+    // Our own refs are restored, and refs that have moved on (or been deleted)
+    // upstream since are kept in the same way as when they're fetched:
+    for (name, id) in &intact {
+        if name.starts_with(PRESERVED_REFS_PREFIX) {
+            backend::set_ref(opts, name, id)?;
+        }
+    }
+    let preserved = super::preserve_history(opts, &intact, &fresh, is_shallow, Utc::now())?;
+    repair.salvaged = intact.keys()
+        .filter(|name| name.starts_with(PRESERVED_REFS_PREFIX) || preserved.iter().any(|r| &r.name == *name))
+        .cloned()
+        .collect();
+    outcome.preserved.extend(preserved);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_looks_corrupt() {
+        let corrupt = vec![
+            "error: HEAD: invalid sha1 pointer 2e1f1b4fd10766d08182d1332e26c53fdc0e4872",
+            "fatal: bad object refs/heads/master",
+            "error: refs/heads/master: object corrupt or missing: 2e1f1b4fd10766d08182d1332e26c53fdc0e4872",
+            "fatal: packfile ./objects/pack/pack-1234.pack cannot be accessed",
+            "object not found - no match for id (2e1f1b4fd10766d08182d1332e26c53fdc0e4872)",
+            "error: inflate: data stream error (incorrect header check)",
+        ];
+        for error in corrupt {
+            assert!(looks_corrupt(error), "error should look like corruption: {}", error);
+        }
+
+        let other = vec![
+            "fatal: Authentication failed for 'https://github.com/jsdw/git-backup.git/'",
+            "fatal: repository 'https://github.com/jsdw/nope.git/' not found",
+            "Timed out after 60 seconds",
+        ];
+        for error in other {
+            assert!(!looks_corrupt(error), "error should not look like corruption: {}", error);
+        }
+    }
+
+}
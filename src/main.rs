@@ -4,24 +4,45 @@ mod error;
 mod logging;
 mod services;
 mod git;
+mod source;
+mod config;
+mod report;
+mod filter;
 
 use colored::*;
 use error::Error;
 use rayon::prelude::*;
-use std::path::PathBuf;
+use regex::Regex;
+use lazy_static::lazy_static;
+use std::path::{ Path, PathBuf };
 use std::collections::HashSet;
 use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::Duration;
+use chrono::{ DateTime, Utc };
 use structopt::StructOpt;
-use services::{ GitHub, GitHubGists, GitLab, Bitbucket, Service };
+use structopt::clap::AppSettings;
+use services::Repository;
+use source::{ Source, Token };
+use config::{ Config, StrategyRule };
+use filter::{ Filter, Pattern, Expr };
+use report::{ Report, SourceReport, RepositoryReport, SubmoduleReport, SyncStatus };
+use git::{ RefSelection, CloneStrategy, LfsOutcome, Verification, Repair, MaintenancePolicy, MaintenanceOutcome, SharingOutcome, BundleTarget, BundleOutcome };
 
 #[derive(StructOpt, Debug)]
-#[structopt(name = "git-backup", author = "James Wilson <james@jsdw.me>")]
+#[structopt(
+    name = "git-backup",
+    author = "James Wilson <james@jsdw.me>",
+    setting = AppSettings::ArgsNegateSubcommands
+)]
 struct Opts {
-    /// URL of repositories to backup
+    #[structopt(subcommand)]
+    command: Option<Command>,
+    /// URL of repositories to backup. This is not needed if
+    /// sources are provided using --source or a config file.
     #[structopt(name="source")]
-    url: String,
-    /// Location to place the backups. If not provided,
-    /// the current working directory will be used
+    url: Option<String>,
+    /// Location to place the backups. If not provided, the destination
+    /// in the config file or the current working directory will be used
     #[structopt(name="destination", parse(from_os_str))]
     backup_location: Option<PathBuf>,
     /// An access token for the service you're trying to backup from.
@@ -29,121 +50,729 @@ struct Opts {
     /// instead, but is required in one of those forms.
     #[structopt(long="token")]
     token: Option<String>,
+    /// The service that the source belongs to, if it can't be worked out from
+    /// the source itself (eg 'gitlab' for a self-hosted GitLab instance). See
+    /// the 'services' command for the services that are available.
+    #[structopt(long="service")]
+    service: Option<String>,
+    /// A source to backup, of the form 'url=<url>[,name=<folder>][,service=<service>][,<token>]', where
+    /// <token> is one of 'token=<token>', 'token-env=<var>', 'token-file=<path>' or
+    /// 'token-cmd=<command>'. 'ssh-key=<path>' and 'known-hosts=<path>' can also be given
+    /// to clone the repositories from this source over SSH (see --ssh-key), and 'refs=<refs>' to choose
+    /// which refs to back up (see --refs). This can be provided multiple times, and each source is
+    /// backed up into its own folder in the destination (by default named after the URL).
+    /// If a token isn't given, the default token is used. When this is used, the
+    /// destination is the only positional argument. Replaces any sources in the config file.
+    #[structopt(long="source", value_name="source", number_of_values=1)]
+    sources: Vec<Source>,
+    /// Only back up repositories whose names match this pattern. Patterns are globs
+    /// (eg 'work-*') unless prefixed with 're:', in which case they are regular
+    /// expressions (eg 're:^work-[0-9]+$'). Can be provided multiple times, in which
+    /// case repositories matching any of the patterns are backed up.
+    #[structopt(long="include", value_name="pattern", number_of_values=1)]
+    include: Vec<Pattern>,
+    /// Don't back up repositories whose names match this pattern (which is given in
+    /// the same way as for --include). Can be provided multiple times.
+    #[structopt(long="exclude", value_name="pattern", number_of_values=1)]
+    exclude: Vec<Pattern>,
+    /// Only back up repositories for which this expression is true, for example
+    /// 'private && !archived && topic == prod && size < 2GB && age < 3y'. See the
+    /// README for everything that can be used in these expressions.
+    #[structopt(long="filter", value_name="expression")]
+    filter: Option<Expr>,
+    /// Clone repositories over SSH using this private key (for instance, a
+    /// deploy key) rather than over HTTPS using the token. A token is still
+    /// needed to find the repositories to back up.
+    #[structopt(long="ssh-key", value_name="path", parse(from_os_str))]
+    ssh_key: Option<PathBuf>,
+    /// When cloning over SSH, only connect to hosts whose keys are listed
+    /// in this known_hosts file, rather than in ~/.ssh/known_hosts.
+    #[structopt(long="known-hosts", value_name="path", parse(from_os_str))]
+    known_hosts: Option<PathBuf>,
+    /// Which refs to back up: 'all', 'branches-and-tags' or 'default-branch'.
+    /// Defaults to 'branches-and-tags' for GitHub and GitLab, so that the refs
+    /// they keep for pull and merge requests are left out, and 'all' otherwise.
+    #[structopt(long="refs", value_name="refs")]
+    refs: Option<RefSelection>,
     /// Remove folders in the destination that don't correspond to
-    /// repositories that we have found to back up.
-    #[structopt(long="prune")]
+    /// repositories that we have found to back up. Repositories that
+    /// are not backed up because of --include, --exclude or --filter
+    /// are not pruned.
+    #[structopt(long="prune", conflicts_with="no-prune")]
     prune: bool,
+    /// Don't prune anything this time, even if a config file turns it on.
+    #[structopt(long="no-prune")]
+    no_prune: bool,
+    /// Fetch from every repository, even those whose refs haven't
+    /// changed since they were last backed up.
+    #[structopt(long="force-fetch")]
+    force_fetch: bool,
+    /// Fetch the Git LFS objects for every ref in each repository, too.
+    /// This needs Git LFS to be installed.
+    #[structopt(long="lfs", conflicts_with="no-lfs")]
+    lfs: bool,
+    /// Don't fetch any LFS objects this time, even if a config file turns it on.
+    #[structopt(long="no-lfs")]
+    no_lfs: bool,
+    /// Also back up the repositories that submodules (in any ref) point to, if
+    /// they aren't already being backed up. These are placed in an 'external'
+    /// folder in the destination.
+    #[structopt(long="submodules", conflicts_with="no-submodules")]
+    submodules: bool,
+    /// Don't back up any submodules this time, even if a config file turns it on.
+    #[structopt(long="no-submodules")]
+    no_submodules: bool,
+    /// Give up on fetching from a repository (or listing its refs, or fetching its
+    /// LFS objects) if it goes this many seconds without making any progress. By
+    /// default, there's no limit.
+    #[structopt(long="timeout", value_name="seconds")]
+    timeout: Option<u64>,
+    /// How many times to try syncing a repository again if it fails for a reason
+    /// that might not last, like a dropped connection or a server error. Each
+    /// attempt waits twice as long as the last before starting. Defaults to 2.
+    #[structopt(long="retries", value_name="count")]
+    retries: Option<u32>,
+    /// Check each backup once it's been synced, to make sure that it's intact
+    /// and has every ref that the repository has. Backups that aren't are
+    /// reported, and cause us to exit with a non-zero status.
+    #[structopt(long="verify", conflicts_with="no-verify")]
+    verify: bool,
+    /// Don't check any backups this time, even if a config file turns it on.
+    #[structopt(long="no-verify")]
+    no_verify: bool,
+    /// Tidy up each backup once it's been synced: pack up loose objects and
+    /// small packs, write a multi-pack-index and commit-graph, and run a full
+    /// 'git gc' on each backup every 30 days (spread out across runs). A
+    /// [maintenance] table in a config file can change this. This needs git
+    /// 2.33 or later, even when using libgit2.
+    #[structopt(long="maintenance", conflicts_with="no-maintenance")]
+    maintenance: bool,
+    /// Don't run maintenance on any backups this time, even if a config
+    /// file turns it on.
+    #[structopt(long="no-maintenance")]
+    no_maintenance: bool,
+    /// Share objects between related backups (forks, and the same repository
+    /// backed up from different places) rather than keeping a copy in each. They're
+    /// kept in pool repositories in an 'objects-pool' folder in the destination,
+    /// which the backups then depend on. This needs git, even when using libgit2.
+    #[structopt(long="share-objects", conflicts_with="no-share-objects")]
+    share_objects: bool,
+    /// Don't share the objects of any backups this time, even if a config
+    /// file turns it on.
+    #[structopt(long="no-share-objects")]
+    no_share_objects: bool,
+    /// Write every ref in each backup into a single bundle file once it's been
+    /// synced (if its refs have changed), and check it with 'git bundle verify'.
+    /// Bundles are placed alongside the backups, eg 'foo.bundle' next to 'foo.git'.
+    /// This needs git, even when using libgit2.
+    #[structopt(long="bundle", conflicts_with="no-bundle")]
+    bundle: bool,
+    /// Don't write any bundles this time, even if a config file turns it on.
+    #[structopt(long="no-bundle", conflicts_with_all=&["bundle-dir", "incremental-bundles"])]
+    no_bundle: bool,
+    /// Place bundles in this folder rather than alongside the backups, in the
+    /// same layout as the destination. Implies --bundle.
+    #[structopt(long="bundle-dir", value_name="folder", parse(from_os_str))]
+    bundle_dir: Option<PathBuf>,
+    /// Rather than writing each bundle again whenever the refs change, keep a chain
+    /// of bundles for each backup in a folder, eg 'foo.bundles'. The first has
+    /// everything in the backup, and each after it has just what's new since the
+    /// last. The 'reassemble' command puts a backup back together from one of
+    /// these. Implies --bundle.
+    #[structopt(long="incremental-bundles")]
+    incremental_bundles: bool,
     /// Don't actually back anything up; just log what we'll do.
     #[structopt(long="dry-run")]
-    dry_run: bool
+    dry_run: bool,
+    /// How many repositories to sync at once. Defaults to the
+    /// number of CPUs.
+    #[structopt(long="jobs")]
+    jobs: Option<usize>,
+    /// A TOML config file describing the sources to backup and how to
+    /// back them up. Any options provided on the command line will
+    /// override those given in the file.
+    #[structopt(long="config", parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Write a JSON report to this file, detailing each repository
+    /// that we found (and what the service told us about it) and
+    /// whether it was backed up successfully.
+    #[structopt(long="report", parse(from_os_str))]
+    report: Option<PathBuf>
 }
 
-fn main() {
-    if let Err(e) = run() {
-        log_error!("{}", e);
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// List the services that we can backup repositories from, along
+    /// with examples of the sources that each one accepts.
+    #[structopt(name="services")]
+    Services,
+    /// Check that the backups in a destination are intact, without syncing
+    /// them. Exits with a non-zero status if any of them aren't.
+    #[structopt(name="verify")]
+    Verify {
+        /// The destination to check the backups in. Defaults to the
+        /// current working directory.
+        #[structopt(name="destination", parse(from_os_str))]
+        destination: Option<PathBuf>,
+        /// How many backups to check at once. Defaults to the
+        /// number of CPUs.
+        #[structopt(long="jobs")]
+        jobs: Option<usize>
+    },
+    /// Put a backup back together from a chain of bundles written using
+    /// --incremental-bundles, as it was when the last bundle was written.
+    #[structopt(name="reassemble")]
+    Reassemble {
+        /// The folder holding the chain of bundles, eg 'foo.bundles'.
+        #[structopt(name="bundles", parse(from_os_str))]
+        bundles: PathBuf,
+        /// Where to place the reassembled backup. This must not already
+        /// exist, or be an empty folder.
+        #[structopt(name="destination", parse(from_os_str))]
+        destination: PathBuf
     }
 }
 
-fn run() -> Result<(),Error> {
+/// Everything we need to back up the repositories from a single source.
+struct Backup {
+    /// The source URL
+    url: String,
+    /// The folder name for this source, if it has its own folder
+    folder: Option<String>,
+    /// Where to place the backups from this source
+    dest_path: PathBuf,
+    /// Should we prune old backups from the destination?
+    prune: bool,
+    username: String,
+    token: String,
+    /// Clone repositories over SSH using these, if provided
+    ssh: Option<git::SshOpts>,
+    /// Which refs to back up from each repository
+    refs: RefSelection,
+    /// Should we fetch LFS objects?
+    lfs: bool,
+    /// Should we back up submodules too?
+    submodules: bool,
+    /// Should we run maintenance on the backups?
+    maintenance: bool,
+    /// The repositories to back up
+    repos: Vec<Repository>,
+    /// Repositories that were filtered out and won't be backed
+    /// up, along with the reason why
+    excluded: Vec<(Repository,String)>,
+    /// Number of repositories synced successfully that had changed
+    updated_count: AtomicU64,
+    /// Number of repositories that hadn't changed, so weren't fetched from
+    unchanged_count: AtomicU64,
+    /// Number of non-fatal errors encountered
+    error_count: AtomicU64
+}
 
-    // Check that we have a valid version of git installed:
-    let git_version = git::version().map_err(|_| err!("Git does not appear to be installed"))?;
-    if git_version < git::Version::new(2,0,0) {
-        return Err(err!("Your version of git appears to be too old. This command requires at least 2.0.0"))
+impl Backup {
+    fn display_name(&self, repo_name: &str) -> String {
+        match &self.folder {
+            Some(folder) => format!("{}/{}", folder, repo_name),
+            None => repo_name.to_owned()
+        }
     }
 
-    // Prepare our options:
+    /// Where to place the bundle (or chain of bundles) of the backup in the folder
+    /// given. Bundles in a separate folder are laid out in the same way as the backups.
+    fn bundle_path(&self, repo_folder: &str, settings: SyncSettings) -> PathBuf {
+        let folder = match (settings.bundle_dir, &self.folder) {
+            (Some(bundle_dir), Some(folder)) => bundle_dir.join(folder),
+            (Some(bundle_dir), None) => bundle_dir.to_owned(),
+            (None, _) => self.dest_path.clone()
+        };
+        folder.join(repo_folder).with_extension(if settings.incremental_bundles { "bundles" } else { "bundle" })
+    }
+}
+
+fn main() {
+    match run() {
+        Ok(true) => {},
+        // Some backups failed verification, which has already been reported:
+        Ok(false) => std::process::exit(1),
+        Err(e) => log_error!("{}", e)
+    }
+}
+
+/// Hands back whether every backup that we checked (if any) is intact.
+fn run() -> Result<bool,Error> {
+
+    // Prepare our options. Those given on the command line override
+    // any in the config file:
     let opts = Opts::from_args();
+    match opts.command {
+        Some(Command::Services) => {
+            list_services();
+            return Ok(true)
+        },
+        Some(Command::Verify { destination, jobs }) => {
+            git::check_available()?;
+            let destination = destination.unwrap_or_else(|| std::env::current_dir().unwrap());
+            return verify_backups(&destination, jobs)
+        },
+        Some(Command::Reassemble { bundles, destination }) => {
+            git::check_bundles_available()?;
+            let count = git::reassemble(&bundles, &destination)?;
+            log_info!("{}", format!("Reassembled '{}' from {} {}", destination.to_string_lossy(),
+                count, if count == 1 { "bundle" } else { "bundles" }).bold().green());
+            return Ok(true)
+        },
+        None => {}
+    }
+
+    // Check that we're able to sync repositories:
+    git::check_available()?;
+
+    let config = match &opts.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default()
+    };
     let dry_run = opts.dry_run;
-    let prune = opts.prune;
-    let url = opts.url;
-    let token = opts.token
-        .or_else(|| std::env::var("GIT_TOKEN").ok())
-        .ok_or_else(|| err!("Need either --token or GIT_TOKEN env var to be provided"))?;
-    let dest_path = opts.backup_location
-        .unwrap_or_else(|| std::env::current_dir().unwrap());
+    let force_fetch = opts.force_fetch;
+    let prune = if opts.no_prune { Some(false) } else if opts.prune { Some(true) } else { config.prune };
+    let lfs = if opts.no_lfs { Some(false) } else if opts.lfs { Some(true) } else { config.lfs };
+    let submodules = if opts.no_submodules { Some(false) } else if opts.submodules { Some(true) } else { config.submodules };
+    let filter = Filter {
+        include: if opts.include.is_empty() { config.include } else { opts.include },
+        exclude: if opts.exclude.is_empty() { config.exclude } else { opts.exclude },
+        expr: opts.filter.or(config.filter)
+    };
+    let default_token = opts.token.map(Token::Value)
+        .or(config.token)
+        .or_else(|| std::env::var("GIT_TOKEN").ok().map(Token::Value));
+    let default_ssh_key = opts.ssh_key.or(config.ssh_key);
+    let default_known_hosts = opts.known_hosts.or(config.known_hosts);
+    let default_refs = opts.refs.or(config.refs);
+    let strategies = config.strategies;
+    let timeout = match opts.timeout.or(config.timeout) {
+        Some(0) => return Err(err!("--timeout must be at least 1 second")),
+        timeout => timeout.map(Duration::from_secs)
+    };
+    let retries = opts.retries.or(config.retries).unwrap_or(DEFAULT_RETRIES);
+    let verify = !opts.no_verify && (opts.verify || config.verify.unwrap_or(false));
+    let maintenance_policy = config.maintenance.unwrap_or_default();
+    let maintenance = if opts.no_maintenance { Some(false) } else if opts.maintenance { Some(true) } else { None };
+    let share_objects = !opts.no_share_objects && (opts.share_objects || config.share_objects.unwrap_or(false));
+    let bundle_dir = opts.bundle_dir.or(config.bundle_dir);
+    let incremental_bundles = opts.incremental_bundles || config.incremental_bundles.unwrap_or(false);
+    let bundle = !opts.no_bundle && (opts.bundle || bundle_dir.is_some() || incremental_bundles || config.bundle.unwrap_or(false));
 
-    // Find a matching service:
-    let service = pick_service(url.clone(), token.clone())
-        .ok_or_else(|| err!("Source '{}' not recognised", &url))?;
-    let repos = service.list_repositories()?;
-    let username = service.username();
+    if let Some(jobs) = opts.jobs.or(config.jobs) {
+        if jobs == 0 {
+            return Err(err!("--jobs must be at least 1"))
+        }
+        rayon::ThreadPoolBuilder::new().num_threads(jobs).build_global()?;
+    }
 
-    if repos.len() != 1 {
-        log_info!("{}", format!("Backing up {} repositories", repos.len()).blue().bold());
+    // Sources provided via --source or the config file are each given their
+    // own folder, and in this case the only positional argument is the destination:
+    let use_folders = !opts.sources.is_empty() || !config.sources.is_empty();
+    let (sources, backup_location) = if !use_folders {
+        let url = opts.url.ok_or_else(|| err!("A source to backup must be provided"))?;
+        let source = Source { service: opts.service, ..Source::new(url) };
+        (vec![source], opts.backup_location)
+    } else if opts.service.is_some() {
+        return Err(err!("--service can only be used with a single source; use 'service=<service>' with --source instead"))
+    } else if opts.backup_location.is_some() {
+        return Err(err!("Only a destination should be provided when sources are given using --source or a config file"))
+    } else if !opts.sources.is_empty() {
+        (opts.sources, opts.url.map(PathBuf::from))
     } else {
-        log_info!("{}", "Backing up 1 repository".blue().bold());
+        (config.sources, opts.url.map(PathBuf::from))
+    };
+    let dest_path = backup_location
+        .or(config.destination)
+        .unwrap_or_else(|| std::env::current_dir().unwrap());
+    let object_pools = dest_path.join(OBJECT_POOLS_FOLDER);
+    let settings = SyncSettings {
+        force_fetch,
+        timeout,
+        retries,
+        verify,
+        maintenance: maintenance_policy,
+        object_pools: if share_objects { Some(&object_pools) } else { None },
+        bundle,
+        incremental_bundles,
+        bundle_dir: bundle_dir.as_deref()
+    };
+
+    // Sources can't share a folder, else they'd prune each other:
+    let mut seen_folders = HashSet::new();
+    for source in &sources {
+        if use_folders && source.destination.is_none() && !seen_folders.insert(source.folder_name()) {
+            return Err(err!("More than one source would be backed up to the folder '{}'; use 'name=<folder>' to give them different folders", source.folder_name()))
+        }
     }
 
-    // Track non-fatal error count:
-    let error_count = AtomicU64::new(0);
+    // A known hosts file is only used when cloning over SSH:
+    if default_known_hosts.is_some() && default_ssh_key.is_none() && sources.iter().all(|s| s.ssh_key.is_none()) {
+        return Err(err!("A known hosts file was given without an SSH key to use"))
+    }
 
-    // Perform the backup:
-    repos.par_iter().for_each(|repo| {
-        log_info!("{} {}", "Syncing".green(), repo.name);
-        let mut repo_path = dest_path.clone();
-        repo_path.push(repo_name_to_folder(&repo.name));
+    // Track non-fatal errors that happen outside of a source:
+    let error_count = AtomicU64::new(0);
 
-        if !dry_run {
-            let sync_result = git::sync_repository(git::Opts {
-                repo_url: &repo.git_url,
-                username: &username,
-                password: &token,
-                destination: &repo_path
-            });
-            if let Err(e) = sync_result {
+    // Find the repositories to backup for each source:
+    let mut backups = vec![];
+    let mut failed_sources = vec![];
+    for mut source in sources {
+        // Sources that have their own destination don't need a folder
+        // in the main one:
+        let source_dest_path = match &source.destination {
+            Some(path) => path.to_owned(),
+            None if use_folders => dest_path.join(source.folder_name()),
+            None => dest_path.clone()
+        };
+        let source_prune = if opts.no_prune { false } else if opts.prune { true } else { source.prune.or(prune).unwrap_or(false) };
+        let source_filter = filter.and(&source.filter());
+        let source_ssh_key = source.ssh_key.as_ref().or(default_ssh_key.as_ref());
+        if source.known_hosts.is_some() && source_ssh_key.is_none() {
+            return Err(err!("A known hosts file was given for '{}' without an SSH key to use", source.url))
+        }
+        let source_ssh = ssh_opts(source_ssh_key, source.known_hosts.as_ref().or(default_known_hosts.as_ref()))?;
+        // If nothing says otherwise, the service decides which refs to back up:
+        source.refs = source.refs.or(default_refs);
+        source.lfs = if opts.no_lfs || opts.lfs { lfs } else { source.lfs.or(lfs) };
+        source.submodules = if opts.no_submodules || opts.submodules { submodules } else { source.submodules.or(submodules) };
+        source.maintenance = maintenance.or(source.maintenance).or(Some(maintenance_policy.enabled));
+        match prepare_backup(&source, source_dest_path, source_prune, &source_filter, source_ssh, use_folders, default_token.as_ref()) {
+            Ok(backup) => backups.push(backup),
+            Err(e) => {
                 error_count.fetch_add(1, Ordering::Relaxed);
-                log_error!("Could not sync repository '{}': \n{}", repo_path.to_string_lossy(), e);
+                log_error!("Could not list repositories for '{}': {}", source.url, e);
+                failed_sources.push((source.url, e.to_string()));
             }
         }
+    }
 
-    });
+    // Fetching LFS objects needs git-lfs, even when using libgit2:
+    if backups.iter().any(|b| b.lfs) {
+        git::check_lfs_available()?;
+    }
+    // As does running maintenance on backups:
+    if backups.iter().any(|b| b.maintenance) {
+        git::check_maintenance_available()?;
+    }
+    if share_objects {
+        git::check_sharing_available()?;
+    }
+    if bundle {
+        git::check_bundles_available()?;
+    }
 
-    // Prune folders that may have been created with this app
-    // from a prior backup but are now no logner needed.
-    if prune {
-        let keep_these_folders: HashSet<String> = repos
-            .into_iter()
-            .map(|repo| repo_name_to_folder(&repo.name))
-            .collect();
-        for entry in std::fs::read_dir(dest_path)? {
-            // Ignore things we run into an issue reading:
-            let entry = if let Ok(entry) = entry {
-                entry
-            } else {
-                continue
-            };
-            // Ignore non-directories:
-            if !entry.path().is_dir() {
-                continue;
-            }
-            // Ignore non-utf8 filenames (this program wouldn't have created them):
-            let file_name = if let Ok(name) = entry.file_name().into_string() {
-                name
-            } else {
-                continue
-            };
-            // Ignore filenames not ending in '.git':
-            if !file_name.ends_with(".git") {
-                continue
+    // In a dry run, make it clear which repositories we're leaving out:
+    if dry_run {
+        for backup in &backups {
+            for (repo, reason) in &backup.excluded {
+                log_info!("{} {} ({})", "Excluding".yellow(), backup.display_name(&repo.name), reason);
             }
-            // Ignore filenames for current repos:
-            if keep_these_folders.contains(&file_name) {
-                continue
+        }
+    }
+
+    let repo_count: usize = backups.iter().map(|b| b.repos.len()).sum();
+    let excluded_count: usize = backups.iter().map(|b| b.excluded.len()).sum();
+    let excluded_msg = if excluded_count > 0 { format!(" ({} excluded)", excluded_count) } else { String::new() };
+    if repo_count != 1 {
+        log_info!("{}", format!("Backing up {} repositories{}", repo_count, excluded_msg).blue().bold());
+    } else {
+        log_info!("{}", format!("Backing up 1 repository{}", excluded_msg).blue().bold());
+    }
+
+    // Perform the backup, sharing the work across every source:
+    let jobs: Vec<(&Backup, &Repository)> = backups
+        .iter()
+        .flat_map(|backup| backup.repos.iter().map(move |repo| (backup, repo)))
+        .collect();
+    let now = Utc::now();
+    let results: Vec<SyncResult> = jobs.par_iter().map(|(backup, repo)| {
+        log_info!("{} {}", "Syncing".green(), backup.display_name(&repo.name));
+        let repo_path = backup.dest_path.join(repo_name_to_folder(&repo.name));
+        let quarantine = backup.dest_path.join(QUARANTINE_FOLDER);
+        let bundle_path = backup.bundle_path(&repo_name_to_folder(&repo.name), settings);
+        let strategy = clone_strategy(&strategies, repo, now);
+
+        if dry_run {
+            return SyncResult::from(SyncStatus::Skipped)
+        }
+
+        // Repositories can come with their own credentials:
+        let (username, password) = match &repo.credentials {
+            Some(creds) => (&creds.username, &creds.password),
+            None => (&backup.username, &backup.token)
+        };
+        let repo_url = match (&backup.ssh, &repo.ssh_url) {
+            (None, _) => &repo.git_url,
+            (Some(_), Some(ssh_url)) => ssh_url,
+            (Some(_), None) => {
+                backup.error_count.fetch_add(1, Ordering::Relaxed);
+                let e = "An SSH key was given, but the service did not provide an SSH URL to clone this repository from";
+                log_error!("Could not sync repository '{}': \n{}", repo_path.to_string_lossy(), e);
+                return SyncResult::from(SyncStatus::Failed { error: e.to_owned() })
             }
-            // Remove the folder and its contents (if not dry_run):
-            log_info!("{} {}", "Pruning".yellow(), file_name);
-            if !dry_run {
-                if let Some(err) = std::fs::remove_dir_all(entry.path()).err() {
-                    error_count.fetch_add(1, Ordering::Relaxed);
-                    log_error!("Error pruning {}: {}", file_name, err);
-                }
+        };
+        let on_progress = |progress: &git::Progress| {
+            log_info!("{} {} ({}/{} objects, {:.1} MB)", "Receiving".cyan(), backup.display_name(&repo.name),
+                progress.received_objects, progress.total_objects, progress.received_bytes as f64 / (1024.0 * 1024.0));
+        };
+        let (sync_result, retried) = sync_with_retries(git::Opts {
+            repo_url,
+            username,
+            password,
+            destination: &repo_path,
+            ssh: backup.ssh.as_ref(),
+            refs: backup.refs,
+            strategy,
+            default_branch: repo.default_branch.as_deref(),
+            timeout: settings.timeout,
+            force_fetch: settings.force_fetch,
+            lfs: backup.lfs,
+            submodules: backup.submodules,
+            verify: settings.verify,
+            quarantine: Some(&quarantine),
+            object_pools: settings.object_pools,
+            maintenance: settings.maintenance_policy(backup),
+            bundle: settings.bundle_target(&bundle_path),
+            on_progress: &on_progress
+        }, settings.retries, &backup.display_name(&repo.name));
+        SyncResult { retried, ..record_sync(backup, &repo.name, &repo_path, strategy, sync_result) }
+    }).collect();
+
+    // Back up any submodules that aren't being backed up already:
+    let submodules = if dry_run { vec![] } else { sync_submodules(&jobs, &results, settings) };
+
+    // How many repositories had history rewritten upstream?
+    let rewritten_count = results.iter().map(|r| &r.status)
+        .chain(submodules.iter().map(|(_, s)| &s.status))
+        .filter(|status| match status {
+            SyncStatus::Synced { preserved } => !preserved.is_empty(),
+            _ => false
+        })
+        .count();
+
+    // How many backups were corrupt, and had to be cloned again?
+    let repaired_count = results.iter().map(|r| &r.repair)
+        .chain(submodules.iter().map(|(_, s)| &s.repair))
+        .filter(|r| r.is_some())
+        .count();
+
+    // How many backups that we checked weren't intact?
+    let unverified_count = results.iter().map(|r| &r.verification)
+        .chain(submodules.iter().map(|(_, s)| &s.verification))
+        .filter(|v| matches!(v, Some(v) if !v.is_ok()))
+        .count();
+
+    // Partial and shallow clones are missing things, so aren't complete backups:
+    let incomplete: Vec<String> = jobs.iter().zip(&results)
+        .filter_map(|((backup, repo), result)| match result.strategy {
+            Some(strategy) if !strategy.is_full() => Some(format!("{} ({})", backup.display_name(&repo.name), strategy)),
+            _ => None
+        })
+        .collect();
+
+    // Add up what happened when fetching LFS objects:
+    let lfs_outcomes: Vec<LfsOutcome> = results.iter().map(|r| &r.lfs)
+        .chain(submodules.iter().map(|(_, s)| &s.lfs))
+        .filter_map(|lfs| lfs.clone())
+        .collect();
+
+    // Add up what happened when sharing objects between backups:
+    let sharing_outcomes: Vec<SharingOutcome> = results.iter().map(|r| &r.sharing)
+        .chain(submodules.iter().map(|(_, s)| &s.sharing))
+        .filter_map(|s| s.clone())
+        .collect();
+
+    // Add up what happened when tidying up backups:
+    let maintenance_outcomes: Vec<MaintenanceOutcome> = results.iter().map(|r| &r.maintenance)
+        .chain(submodules.iter().map(|(_, s)| &s.maintenance))
+        .filter_map(|m| m.clone())
+        .collect();
+
+    // Add up what happened when bundling up backups:
+    let bundle_outcomes: Vec<BundleOutcome> = results.iter().map(|r| &r.bundle)
+        .chain(submodules.iter().map(|(_, s)| &s.bundle))
+        .filter_map(|b| b.clone())
+        .collect();
+
+    // Prune folders that may have been created with this app
+    // from a prior backup but are now no logner needed. Each source
+    // only prunes within its own folder.
+    let pruned: Vec<Vec<String>> = backups.iter()
+        .map(|backup| if backup.prune { prune_backup(backup, settings, dry_run) } else { vec![] })
+        .collect();
+
+    // Write out a report if asked for one:
+    if let Some(report_path) = &opts.report {
+        let mut report = Report::default();
+        let mut results = jobs.iter().zip(results);
+        let mut submodules = submodules;
+        for (backup, pruned) in backups.iter().zip(pruned) {
+            let excluded = backup.excluded
+                .iter()
+                .map(|(repo, reason)| (repo, SyncResult::from(SyncStatus::Excluded { reason: reason.clone() })));
+            let repositories = results
+                .by_ref()
+                .take(backup.repos.len())
+                .map(|((_, repo), result)| (*repo, result))
+                .chain(excluded)
+                .map(|(repo, result)| RepositoryReport {
+                    repository: repo,
+                    path: backup.dest_path.join(repo_name_to_folder(&repo.name)),
+                    status: result.status,
+                    retried: result.retried,
+                    strategy: result.strategy.filter(|s| !s.is_full()),
+                    lfs: result.lfs,
+                    verification: result.verification,
+                    repair: result.repair,
+                    sharing: result.sharing,
+                    maintenance: result.maintenance,
+                    bundle: result.bundle
+                })
+                .collect();
+            let (these, others) = submodules.into_iter().partition(|(b, _)| std::ptr::eq(*b, backup));
+            submodules = others;
+            report.sources.push(SourceReport {
+                url: backup.url.clone(),
+                destination: Some(backup.dest_path.clone()),
+                error: None,
+                repositories,
+                submodules: these.into_iter().map(|(_, s)| s).collect(),
+                pruned
+            });
+        }
+        for (url, error) in failed_sources {
+            report.sources.push(SourceReport {
+                url,
+                destination: None,
+                error: Some(error),
+                repositories: vec![],
+                submodules: vec![],
+                pruned: vec![]
+            });
+        }
+        if let Err(e) = report.write(report_path) {
+            error_count.fetch_add(1, Ordering::Relaxed);
+            log_error!("{}", e);
+        }
+    }
+
+    // Log a summary for each source if there are several:
+    if use_folders && backups.len() > 1 {
+        for backup in &backups {
+            let updated_count = backup.updated_count.load(Ordering::Relaxed);
+            let unchanged_count = backup.unchanged_count.load(Ordering::Relaxed);
+            let error_count = backup.error_count.load(Ordering::Relaxed);
+            let summary = format!("{}: {} updated, {} unchanged, {} {}",
+                backup.folder.as_ref().unwrap(),
+                updated_count,
+                unchanged_count,
+                error_count,
+                if error_count == 1 { "error" } else { "errors" }
+            );
+            if error_count > 0 {
+                log_info!("{}", summary.red());
+            } else {
+                log_info!("{}", summary.green());
             }
         }
     }
 
+    // Make sure that rewritten history doesn't go unnoticed:
+    if rewritten_count > 0 {
+        let repositories = if rewritten_count == 1 { "repository" } else { "repositories" };
+        log_info!("{}", format!("History was rewritten upstream in {} {}; the old history has been kept under refs/git-backup/", rewritten_count, repositories).yellow());
+    }
+
+    if !incomplete.is_empty() {
+        let repositories = if incomplete.len() == 1 { "repository is" } else { "repositories are" };
+        log_info!("{}", format!("{} {} partial or shallow clones, so not complete backups: {}",
+            incomplete.len(), repositories, incomplete.join(", ")).yellow());
+    }
+
+    // LFS objects are reported on separately from the repositories themselves:
+    let lfs_failed_count = lfs_outcomes.iter().filter(|o| matches!(o, LfsOutcome::Failed { .. })).count();
+    if lfs_outcomes.len() > lfs_failed_count {
+        let (size, fetched) = lfs_outcomes.iter().fold((0, 0), |(size, fetched), outcome| match outcome {
+            LfsOutcome::Fetched { size: s, fetched: f } => (size + s, fetched + f),
+            LfsOutcome::Failed { .. } => (size, fetched)
+        });
+        log_info!("{}", format!("Fetched {:.1} MB of LFS objects ({:.1} MB in total)",
+            fetched as f64 / (1024.0 * 1024.0), size as f64 / (1024.0 * 1024.0)).blue());
+    }
+    if lfs_failed_count > 0 {
+        let repositories = if lfs_failed_count == 1 { "repository" } else { "repositories" };
+        log_info!("{}", format!("Could not fetch LFS objects for {} {}", lfs_failed_count, repositories).red());
+    }
+
+    // As is sharing objects between backups:
+    let sharing_failed_count = sharing_outcomes.iter().filter(|o| matches!(o, SharingOutcome::Failed { .. })).count();
+    if sharing_outcomes.len() > sharing_failed_count {
+        let shared_count = sharing_outcomes.len() - sharing_failed_count;
+        let saved: u64 = sharing_outcomes.iter().map(|o| o.saved()).sum();
+        log_info!("{}", format!("Shared objects between related backups for {} {}, saving {:.1} MB",
+            shared_count, if shared_count == 1 { "backup" } else { "backups" }, saved as f64 / (1024.0 * 1024.0)).blue());
+    }
+    if sharing_failed_count > 0 {
+        let backups = if sharing_failed_count == 1 { "backup" } else { "backups" };
+        log_info!("{}", format!("Could not share objects for {} {}", sharing_failed_count, backups).red());
+    }
+
+    // Maintenance is reported on separately from the repositories too:
+    let maintenance_failed_count = maintenance_outcomes.iter().filter(|o| matches!(o, MaintenanceOutcome::Failed { .. })).count();
+    if maintenance_outcomes.len() > maintenance_failed_count {
+        let done_count = maintenance_outcomes.len() - maintenance_failed_count;
+        let gc_count = maintenance_outcomes.iter()
+            .filter(|o| matches!(o, MaintenanceOutcome::Done { tasks, .. } if tasks.iter().any(|t| t == "gc")))
+            .count();
+        let reclaimed: u64 = maintenance_outcomes.iter().map(|o| o.reclaimed()).sum();
+        let gc_msg = if gc_count > 0 { format!(" ({} with a full gc)", gc_count) } else { String::new() };
+        log_info!("{}", format!("Ran maintenance on {} {}{}, reclaiming {:.1} MB",
+            done_count, if done_count == 1 { "backup" } else { "backups" }, gc_msg, reclaimed as f64 / (1024.0 * 1024.0)).blue());
+    }
+    if maintenance_failed_count > 0 {
+        let backups = if maintenance_failed_count == 1 { "backup" } else { "backups" };
+        log_info!("{}", format!("Could not run maintenance on {} {}", maintenance_failed_count, backups).red());
+    }
+
+    // As is bundling them up:
+    let bundle_failed_count = bundle_outcomes.iter().filter(|o| matches!(o, BundleOutcome::Failed { .. })).count();
+    if bundle_outcomes.len() > bundle_failed_count {
+        let written_count = bundle_outcomes.len() - bundle_failed_count;
+        let incremental_count = bundle_outcomes.iter().filter(|o| matches!(o, BundleOutcome::Written { incremental: true, .. })).count();
+        let size: u64 = bundle_outcomes.iter().map(|o| match o {
+            BundleOutcome::Written { size, .. } => *size,
+            BundleOutcome::Failed { .. } => 0
+        }).sum();
+        let incremental_msg = if incremental_count > 0 { format!(" ({} incremental)", incremental_count) } else { String::new() };
+        log_info!("{}", format!("Wrote {} {}{}, {:.1} MB in total",
+            written_count, if written_count == 1 { "bundle" } else { "bundles" }, incremental_msg, size as f64 / (1024.0 * 1024.0)).blue());
+    }
+    if bundle_failed_count > 0 {
+        let backups = if bundle_failed_count == 1 { "backup" } else { "backups" };
+        log_info!("{}", format!("Could not bundle up {} {}", bundle_failed_count, backups).red());
+    }
+
+    // Repositories that haven't changed upstream aren't fetched from:
+    if !dry_run {
+        let updated_count: u64 = backups.iter().map(|b| b.updated_count.load(Ordering::Relaxed)).sum();
+        let unchanged_count: u64 = backups.iter().map(|b| b.unchanged_count.load(Ordering::Relaxed)).sum();
+        log_info!("{}", format!("{} updated, {} unchanged", updated_count, unchanged_count).blue());
+    }
+
+    if repaired_count > 0 {
+        let backups = if repaired_count == 1 { "backup was" } else { "backups were" };
+        log_info!("{}", format!("{} corrupt {} cloned again; the damaged copies have been kept in '{}' folders", repaired_count, backups, QUARANTINE_FOLDER).yellow());
+    }
+    if unverified_count > 0 {
+        let backups = if unverified_count == 1 { "backup is" } else { "backups are" };
+        log_info!("{}", format!("{} {} corrupt or incomplete", unverified_count, backups).red());
+    }
+
     // Log final summary:
-    let error_count = error_count.load(Ordering::Relaxed);
+    let error_count = error_count.load(Ordering::Relaxed)
+        + backups.iter().map(|b| b.error_count.load(Ordering::Relaxed)).sum::<u64>();
     if error_count > 1 {
         log_info!("{}", format!("Backup completed with {} errors", error_count).bold().red());
     } else if error_count > 0 {
@@ -152,35 +781,572 @@ fn run() -> Result<(),Error> {
         log_info!("{}", "Backup completed!".bold().green());
     }
 
+    Ok(unverified_count == 0)
+}
+
+/// Check every backup in a destination (including those in folders within it),
+/// logging any that aren't intact. Hands back whether they all are.
+fn verify_backups(destination: &Path, jobs: Option<usize>) -> Result<bool,Error> {
+    if let Some(jobs) = jobs {
+        if jobs == 0 {
+            return Err(err!("--jobs must be at least 1"))
+        }
+        rayon::ThreadPoolBuilder::new().num_threads(jobs).build_global()?;
+    }
+
+    let mut paths = vec![];
+    find_backups(destination, &mut paths)?;
+    paths.sort();
+    log_info!("{}", format!("Verifying {} {}", paths.len(), if paths.len() == 1 { "backup" } else { "backups" }).blue().bold());
+
+    let failed_count = paths.par_iter()
+        .filter(|path| {
+            let name = path.strip_prefix(destination).unwrap_or(path).to_string_lossy();
+            log_info!("{} {}", "Verifying".green(), name);
+            let verification = git::verify_repository(path);
+            log_verification(&name, &verification);
+            !verification.is_ok()
+        })
+        .count();
+
+    if failed_count > 0 {
+        let backups = if failed_count == 1 { "backup is" } else { "backups are" };
+        log_info!("{}", format!("{} {} corrupt", failed_count, backups).bold().red());
+    } else {
+        log_info!("{}", "Every backup is intact!".bold().green());
+    }
+    Ok(failed_count == 0)
+}
+
+/// Find the backups in a folder, and in any folders within it that aren't backups.
+fn find_backups(path: &Path, backups: &mut Vec<PathBuf>) -> Result<(),Error> {
+    let entries = std::fs::read_dir(path)
+        .map_err(|e| err!("Could not read '{}': {}", path.to_string_lossy(), e))?;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if !entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+            continue
+        }
+        if git::is_backup(&path) {
+            backups.push(path);
+        } else if !path.join("HEAD").is_file() {
+            // Look in folders of backups, but not in anything else that git
+            // made (like the damaged backups that have been quarantined):
+            find_backups(&path, backups)?;
+        }
+    }
     Ok(())
 }
 
+/// Log what we found when checking a backup, if there's anything wrong with it.
+fn log_verification(name: &str, verification: &Verification) {
+    match verification {
+        Verification::Ok => {},
+        Verification::Corrupt { error } => {
+            log_error!("Backup '{}' is corrupt: \n{}", name, error.trim());
+        },
+        Verification::Incomplete { missing } => {
+            log_error!("Backup '{}' is missing refs that the repository has: {}", name, missing.join(", "));
+        }
+    }
+}
+
+/// Find the service for a source and list the repositories that need backing up.
+fn prepare_backup(source: &Source, dest_path: PathBuf, prune: bool, filter: &Filter, ssh: Option<git::SshOpts>, use_folder: bool, default_token: Option<&Token>) -> Result<Backup,Error> {
+    let token = source.token.as_ref()
+        .or(default_token)
+        .ok_or_else(|| err!("Need a token to be provided using --token, the GIT_TOKEN env var or a config file"))?
+        .resolve()?;
+
+    let service = services::pick_service(source.url.clone(), token.clone(), source.service.as_deref())?;
+    let now = Utc::now();
+
+    // Leave out whatever the service leaves out by default, unless we've
+    // been told something about the same flags:
+    let flags = filter.expr.as_ref().map(|expr| expr.flags()).unwrap_or_default();
+    let filter = match service.default_filter() {
+        Some(expr) if !expr.flags().iter().any(|flag| flags.contains(flag)) => filter.and(&Filter { expr: Some(expr), ..Filter::default() }),
+        _ => filter.clone()
+    };
+
+    let mut repos = vec![];
+    let mut excluded = vec![];
+    let mut invalid_count = 0;
+    for repo in service.list_repositories()? {
+        if let Err(e) = check_repo_name(&repo.name) {
+            log_error!("Not backing up '{}' from '{}': {}", repo.name, source.url, e);
+            invalid_count += 1;
+            continue
+        }
+        match filter.check(&repo, now) {
+            Ok(()) => repos.push(repo),
+            Err(reason) => excluded.push((repo, reason))
+        }
+    }
+    let username = service.username();
+    let refs = source.refs.unwrap_or_else(|| service.default_refs());
+    let lfs = source.lfs.unwrap_or(false);
+    let submodules = source.submodules.unwrap_or(false);
+    let maintenance = source.maintenance.unwrap_or(false);
+
+    let folder = if use_folder { Some(source.folder_name()) } else { None };
+
+    Ok(Backup {
+        url: source.url.clone(),
+        folder,
+        dest_path,
+        prune,
+        username,
+        token,
+        ssh,
+        refs,
+        lfs,
+        submodules,
+        maintenance,
+        repos,
+        excluded,
+        updated_count: AtomicU64::new(0),
+        unchanged_count: AtomicU64::new(0),
+        error_count: AtomicU64::new(invalid_count)
+    })
+}
+
+/// A submodule to back up, because a repository that we're backing up refers to it.
+struct Submodule<'a> {
+    backup: &'a Backup,
+    url: String,
+    /// The URL of the repository that refers to it
+    parent_url: String,
+    username: &'a str,
+    password: &'a str,
+    ssh: Option<&'a git::SshOpts>
+}
+
+/// Back up the submodules that the repositories we've synced refer to (and the
+/// submodules that those refer to, and so on), unless they're already being
+/// backed up. They're placed in an 'external' folder in each destination.
+fn sync_submodules<'a>(jobs: &[(&'a Backup, &'a Repository)], results: &[SyncResult], settings: SyncSettings) -> Vec<(&'a Backup, SubmoduleReport)> {
+
+    // Never back up the same repository twice, which also stops us
+    // going round in circles if submodules refer to each other:
+    let mut seen: HashSet<String> = jobs.iter()
+        .flat_map(|(_, repo)| std::iter::once(&repo.git_url).chain(repo.ssh_url.iter()))
+        .map(|url| git::url_key(url))
+        .collect();
+
+    let mut queue = vec![];
+    for ((backup, repo), result) in jobs.iter().zip(results) {
+        if !backup.submodules {
+            continue
+        }
+        let (username, password) = match &repo.credentials {
+            Some(creds) => (&*creds.username, &*creds.password),
+            None => (&*backup.username, &*backup.token)
+        };
+        let parent = Submodule { backup, url: repo.git_url.clone(), parent_url: String::new(), username, password, ssh: backup.ssh.as_ref() };
+        for url in &result.submodules {
+            queue.extend(next_submodule(&parent, url, &mut seen));
+        }
+    }
+
+    let mut reports = vec![];
+    while !queue.is_empty() {
+        let results: Vec<(PathBuf, SyncResult)> = queue.par_iter().map(|submodule: &Submodule| {
+            let name = format!("{}/{}", EXTERNAL_FOLDER, submodule_folder(&submodule.url));
+            let path = submodule.backup.dest_path.join(repo_name_to_folder(&name));
+            let quarantine = submodule.backup.dest_path.join(QUARANTINE_FOLDER);
+            let bundle_path = submodule.backup.bundle_path(&repo_name_to_folder(&name), settings);
+            log_info!("{} {} (a submodule of {})", "Syncing".green(), submodule.backup.display_name(&name), submodule.parent_url);
+            let (sync_result, retried) = sync_with_retries(git::Opts {
+                repo_url: &submodule.url,
+                username: submodule.username,
+                password: submodule.password,
+                destination: &path,
+                ssh: submodule.ssh,
+                refs: RefSelection::BranchesAndTags,
+                strategy: CloneStrategy::Full,
+                default_branch: None,
+                timeout: settings.timeout,
+                force_fetch: settings.force_fetch,
+                lfs: submodule.backup.lfs,
+                submodules: true,
+                verify: settings.verify,
+                quarantine: Some(&quarantine),
+                object_pools: settings.object_pools,
+                maintenance: settings.maintenance_policy(submodule.backup),
+                bundle: settings.bundle_target(&bundle_path),
+                on_progress: &|_| {}
+            }, settings.retries, &submodule.backup.display_name(&name));
+            let result = SyncResult { retried, ..record_sync(submodule.backup, &name, &path, CloneStrategy::Full, sync_result) };
+            (path, result)
+        }).collect();
+
+        let mut next = vec![];
+        for (submodule, (path, result)) in queue.iter().zip(results) {
+            for url in &result.submodules {
+                next.extend(next_submodule(submodule, url, &mut seen));
+            }
+            reports.push((submodule.backup, SubmoduleReport {
+                url: submodule.url.clone(),
+                path,
+                status: result.status,
+                retried: result.retried,
+                lfs: result.lfs,
+                verification: result.verification,
+                repair: result.repair,
+                sharing: result.sharing,
+                maintenance: result.maintenance,
+                bundle: result.bundle
+            }));
+        }
+        queue = next;
+    }
+    reports
+}
+
+/// A submodule of the one given that needs backing up, if any. Credentials
+/// are only handed on to submodules at the same HTTPS host and port, and SSH
+/// keys to submodules on the same host.
+fn next_submodule<'a>(parent: &Submodule<'a>, url: &str, seen: &mut HashSet<String>) -> Option<Submodule<'a>> {
+    // Only local repositories should refer to other local repositories:
+    if url.starts_with("file:") && !parent.url.starts_with("file:") {
+        log_info!("{} submodule {} of {}, since it's a local path", "Skipping".yellow(), url, parent.url);
+        return None
+    }
+    if let Err(reason) = git::check_url(url) {
+        log_info!("{} submodule {} of {}, since {}", "Skipping".yellow(), url, parent.url, reason);
+        return None
+    }
+    if !seen.insert(git::url_key(url)) {
+        return None
+    }
+    let shares_credentials = git::shares_credentials(&parent.url, url);
+    Some(Submodule {
+        backup: parent.backup,
+        url: url.to_owned(),
+        parent_url: parent.url.clone(),
+        username: if shares_credentials { parent.username } else { "" },
+        password: if shares_credentials { parent.password } else { "" },
+        ssh: if git::shares_ssh_key(&parent.url, url) { parent.ssh } else { None }
+    })
+}
+
+/// The folder (in 'external') that a submodule is backed up into.
+fn submodule_folder(url: &str) -> String {
+    lazy_static! {
+        static ref INVALID_CHARS_RE: Regex = Regex::new("[^a-zA-Z0-9_.-]+").unwrap();
+    }
+    INVALID_CHARS_RE.replace_all(&git::url_key(url), "-").trim_matches(|c| c == '-' || c == '.').to_owned()
+}
+
+/// Pick the strategy to clone a repository with; the first rule
+/// that matches it wins, and by default it's cloned in full.
+fn clone_strategy(rules: &[StrategyRule], repo: &Repository, now: DateTime<Utc>) -> CloneStrategy {
+    rules.iter()
+        .find(|rule| rule.filter.eval(repo, now))
+        .map(|rule| rule.strategy)
+        .unwrap_or_default()
+}
+
+/// Corrupt backups are moved into this folder in each destination before they're cloned again.
+const QUARANTINE_FOLDER: &str = "quarantine";
+
+/// Related backups share objects through pool repositories in this folder
+/// (in the destination), if asked to.
+const OBJECT_POOLS_FOLDER: &str = "objects-pool";
+
+/// Submodules that live elsewhere are backed up into this folder in each destination.
+const EXTERNAL_FOLDER: &str = "external";
+
+/// How long to wait before trying to sync a repository again for the first time.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// How many times to try syncing a repository again, unless told otherwise.
+const DEFAULT_RETRIES: u32 = 2;
+
+/// Sync a repository, trying again (waiting twice as long each time) if it fails
+/// for a reason that might not last. Also hands back the errors from any attempts
+/// that failed before the last one.
+fn sync_with_retries(opts: git::Opts, retries: u32, display_name: &str) -> (Result<git::SyncOutcome,Error>, Vec<String>) {
+    let mut failed = vec![];
+    loop {
+        let error = match git::sync_repository(opts) {
+            Err(e) if git::is_transient(&e.to_string()) => e,
+            result => return (result, failed)
+        };
+        if failed.len() as u32 >= retries {
+            let error = if failed.is_empty() { error } else { err!("{}\n(gave up after {} attempts)", error, failed.len() + 1) };
+            return (Err(error), failed)
+        }
+        let delay = RETRY_DELAY * 2u32.pow(failed.len() as u32);
+        log_info!("{} {} in {} seconds: {}", "Retrying".yellow(), display_name, delay.as_secs(), error.to_string().trim());
+        failed.push(error.to_string());
+        std::thread::sleep(delay);
+    }
+}
+
+/// What happened when we synced a repository.
+struct SyncResult {
+    status: SyncStatus,
+    /// The errors from earlier attempts to sync it, which were retried
+    retried: Vec<String>,
+    /// What we found when checking the backup, if we did
+    verification: Option<Verification>,
+    /// What we did about the backup being corrupt, if it was
+    repair: Option<Repair>,
+    /// How the backup was cloned, if we got as far as finding out
+    strategy: Option<CloneStrategy>,
+    lfs: Option<LfsOutcome>,
+    sharing: Option<SharingOutcome>,
+    maintenance: Option<MaintenanceOutcome>,
+    bundle: Option<BundleOutcome>,
+    /// The URLs of any submodules that the repository refers to
+    submodules: Vec<String>
+}
+
+impl From<SyncStatus> for SyncResult {
+    fn from(status: SyncStatus) -> SyncResult {
+        SyncResult { status, retried: vec![], verification: None, repair: None, strategy: None, lfs: None, sharing: None, maintenance: None, bundle: None, submodules: vec![] }
+    }
+}
+
+/// Settings that apply to every repository that we sync.
+#[derive(Clone,Copy)]
+struct SyncSettings<'a> {
+    force_fetch: bool,
+    timeout: Option<Duration>,
+    retries: u32,
+    verify: bool,
+    /// How to run maintenance on the backups that it's turned on for
+    maintenance: MaintenancePolicy,
+    /// Where related backups share objects, if they do
+    object_pools: Option<&'a Path>,
+    /// Should we bundle up each backup, and if so, how and where? Bundles
+    /// are placed alongside the backups if no folder is given.
+    bundle: bool,
+    incremental_bundles: bool,
+    bundle_dir: Option<&'a Path>
+}
+
+impl SyncSettings<'_> {
+    fn maintenance_policy(&self, backup: &Backup) -> Option<MaintenancePolicy> {
+        if backup.maintenance { Some(self.maintenance) } else { None }
+    }
+    fn bundle_target<'p>(&self, bundle_path: &'p Path) -> Option<BundleTarget<'p>> {
+        match (self.bundle, self.incremental_bundles) {
+            (false, _) => None,
+            (true, false) => Some(BundleTarget::File(bundle_path)),
+            (true, true) => Some(BundleTarget::Chain(bundle_path))
+        }
+    }
+}
+
+/// Log and count the outcome of syncing a repository.
+fn record_sync(backup: &Backup, name: &str, repo_path: &Path, strategy: CloneStrategy, sync_result: Result<git::SyncOutcome,Error>) -> SyncResult {
+    let outcome = match sync_result {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            backup.error_count.fetch_add(1, Ordering::Relaxed);
+            log_error!("Could not sync repository '{}': \n{}", repo_path.to_string_lossy(), e);
+            return SyncResult::from(SyncStatus::Failed { error: e.to_string() })
+        }
+    };
+
+    if let Some(repair) = &outcome.repair {
+        log_info!("{} {}, which was corrupt ({}); the damaged backup has been moved to '{}'", "Recloned".yellow(),
+            backup.display_name(name), repair.reason.trim(), repair.quarantined_as.to_string_lossy());
+        if !repair.salvaged.is_empty() {
+            log_info!("{} history from the damaged backup of {}: {}", "Salvaged".yellow(), backup.display_name(name), repair.salvaged.join(", "));
+        }
+        if let Some(error) = &repair.error {
+            log_error!("Could not salvage anything from the damaged backup of '{}': {}", repo_path.to_string_lossy(), error);
+        } else if !repair.lost.is_empty() {
+            log_error!("Could not salvage history from the damaged backup of '{}': {}", repo_path.to_string_lossy(), repair.lost.join(", "));
+        }
+    }
+
+    if let Some(verification) = &outcome.verification {
+        log_verification(&backup.display_name(name), verification);
+    }
+
+    if outcome.recloned {
+        log_info!("{} {} from scratch, since the existing backup was never finished", "Cloned".yellow(), backup.display_name(name));
+    }
+
+    // Backups keep the strategy that they were cloned with:
+    if outcome.strategy != strategy {
+        log_info!("{} {} was cloned using the '{}' strategy rather than '{}'; remove the backup to clone it again",
+            "Note:".yellow(), backup.display_name(name), outcome.strategy, strategy);
+    }
+
+    // Problems with LFS objects are counted separately from failures to sync:
+    match &outcome.lfs {
+        Some(LfsOutcome::Failed { error }) => {
+            log_error!("Could not fetch LFS objects for repository '{}': \n{}", repo_path.to_string_lossy(), error);
+        },
+        Some(LfsOutcome::Fetched { fetched, .. }) if *fetched > 0 => {
+            log_info!("{} {} ({:.1} MB of LFS objects)", "Fetched".cyan(), backup.display_name(name), *fetched as f64 / (1024.0 * 1024.0));
+        },
+        _ => {}
+    }
+    if let Some(SharingOutcome::Failed { error }) = &outcome.sharing {
+        log_error!("Could not share objects with related backups for repository '{}': \n{}", repo_path.to_string_lossy(), error);
+    }
+    if let Some(MaintenanceOutcome::Failed { error }) = &outcome.maintenance {
+        log_error!("Could not run maintenance on repository '{}': \n{}", repo_path.to_string_lossy(), error);
+    }
+    if let Some(BundleOutcome::Failed { error }) = &outcome.bundle {
+        log_error!("Could not bundle up repository '{}': \n{}", repo_path.to_string_lossy(), error);
+    }
+
+    if !outcome.fetched {
+        backup.unchanged_count.fetch_add(1, Ordering::Relaxed);
+        return SyncResult { status: SyncStatus::Unchanged, retried: vec![], verification: outcome.verification, repair: outcome.repair, strategy: Some(outcome.strategy), lfs: outcome.lfs, sharing: outcome.sharing, maintenance: outcome.maintenance, bundle: outcome.bundle, submodules: outcome.submodules }
+    }
+    backup.updated_count.fetch_add(1, Ordering::Relaxed);
+    if !outcome.preserved.is_empty() {
+        let refs: Vec<String> = outcome.preserved.iter()
+            .map(|r| format!("{} ({})", r.name, if r.deleted { "deleted" } else { "force-pushed" }))
+            .collect();
+        log_info!("{} history of {}: {}", "Preserved".yellow(), backup.display_name(name), refs.join(", "));
+    }
+    SyncResult { status: SyncStatus::Synced { preserved: outcome.preserved }, retried: vec![], verification: outcome.verification, repair: outcome.repair, strategy: Some(outcome.strategy), lfs: outcome.lfs, sharing: outcome.sharing, maintenance: outcome.maintenance, bundle: outcome.bundle, submodules: outcome.submodules }
+}
+
+/// Work out how to clone over SSH, if we've been given a key to do so.
+fn ssh_opts(key: Option<&PathBuf>, known_hosts: Option<&PathBuf>) -> Result<Option<git::SshOpts>,Error> {
+    let key = match key {
+        Some(key) => key,
+        None => return Ok(None)
+    };
+    if !key.is_file() {
+        return Err(err!("The SSH key '{}' does not exist", key.to_string_lossy()))
+    }
+    if let Some(known_hosts) = known_hosts {
+        if !known_hosts.is_file() {
+            return Err(err!("The known hosts file '{}' does not exist", known_hosts.to_string_lossy()))
+        }
+    }
+    Ok(Some(git::SshOpts { key: key.to_owned(), known_hosts: known_hosts.cloned() }))
+}
+
+/// Remove any repository folders in the backup destination that
+/// don't correspond to the repositories we're backing up. Returns
+/// the names of the folders that were pruned.
+fn prune_backup(backup: &Backup, settings: SyncSettings, dry_run: bool) -> Vec<String> {
+    // Pools aren't backups, and are removed along with their last member instead:
+    if backup.dest_path.file_name().map(|name| name == OBJECT_POOLS_FOLDER).unwrap_or(false) {
+        backup.error_count.fetch_add(1, Ordering::Relaxed);
+        log_error!("Not pruning '{}', because it looks like a folder of object pools", backup.dest_path.to_string_lossy());
+        return vec![]
+    }
+
+    // Repositories that we've excluded still exist, so keep them:
+    let keep_these_folders: HashSet<String> = backup.repos
+        .iter()
+        .chain(backup.excluded.iter().map(|(repo, _)| repo))
+        .map(|repo| repo_name_to_folder(&repo.name))
+        .collect();
+
+    // Nothing to prune if the folder doesn't exist yet:
+    let entries = match std::fs::read_dir(&backup.dest_path) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return vec![],
+        Err(e) => {
+            backup.error_count.fetch_add(1, Ordering::Relaxed);
+            log_error!("Error reading '{}' to prune: {}", backup.dest_path.to_string_lossy(), e);
+            return vec![]
+        }
+    };
+
+    let mut pruned = vec![];
+    for entry in entries {
+        // Ignore things we run into an issue reading:
+        let entry = if let Ok(entry) = entry {
+            entry
+        } else {
+            continue
+        };
+        // Ignore non-directories:
+        if !entry.path().is_dir() {
+            continue;
+        }
+        // Ignore non-utf8 filenames (this program wouldn't have created them):
+        let file_name = if let Ok(name) = entry.file_name().into_string() {
+            name
+        } else {
+            continue
+        };
+        // Ignore filenames not ending in '.git':
+        if !file_name.ends_with(".git") {
+            continue
+        }
+        // Ignore filenames for current repos:
+        if keep_these_folders.contains(&file_name) {
+            continue
+        }
+        // Remove the folder and its contents (if not dry_run):
+        log_info!("{} {}", "Pruning".yellow(), backup.display_name(&file_name));
+        if !dry_run {
+            // Objects that only this backup needed can then be removed from any pool it shares:
+            if let Err(err) = git::leave_pool(&entry.path()) {
+                backup.error_count.fetch_add(1, Ordering::Relaxed);
+                log_error!("Error removing {} from the pool it shares objects through: {}", file_name, err);
+            }
+            if let Some(err) = std::fs::remove_dir_all(entry.path()).err() {
+                backup.error_count.fetch_add(1, Ordering::Relaxed);
+                log_error!("Error pruning {}: {}", file_name, err);
+                continue
+            }
+            // Along with its bundles, if we've been bundling up backups:
+            let bundle_path = backup.bundle_path(&file_name, settings);
+            if settings.bundle && bundle_path.exists() {
+                let removed = if bundle_path.is_dir() {
+                    std::fs::remove_dir_all(&bundle_path)
+                } else {
+                    std::fs::remove_file(&bundle_path)
+                };
+                if let Err(err) = removed {
+                    backup.error_count.fetch_add(1, Ordering::Relaxed);
+                    log_error!("Error pruning the bundles of {}: {}", file_name, err);
+                }
+            }
+        }
+        pruned.push(file_name);
+    }
+
+    pruned
+}
+
 fn repo_name_to_folder(repo_name: &str) -> String {
     format!("{}.git", repo_name)
 }
 
-fn pick_service(url: String, token: String) -> Option<Box<dyn Service>> {
-    if let Some(res) = GitHub::new(
-        url.clone(),
-        token.clone()
-    ) {
-        Some(Box::new(res))
-    } else if let Some(res) = GitHubGists::new(
-        url.clone(),
-        token.clone()
-    ) {
-        Some(Box::new(res))
-    } else if let Some(res) = Bitbucket::new(
-        url.clone(),
-        token.clone()
-    ) {
-        Some(Box::new(res))
-    } else if let Some(res) = GitLab::new(
-        url.clone(),
-        token.clone()
-    ) {
-        Some(Box::new(res))
+/// Repositories are backed up into a folder named after them, so
+/// a name mustn't lead anywhere outside of the destination.
+fn check_repo_name(repo_name: &str) -> Result<(),Error> {
+    if repo_name.is_empty() || repo_name == "." || repo_name == ".." || repo_name.contains(['/', '\\', '\0']) {
+        return Err(err!("it is not a valid folder name"))
+    }
+    Ok(())
+}
+
+/// Print out the services that are available.
+fn list_services() {
+    for info in services::SERVICES {
+        log_info!("{} - {}", info.name.bold(), info.description);
+        for example in info.examples {
+            log_info!("    {}", example);
+        }
+    }
+
+    let plugins = services::installed_plugins();
+    log_info!("{} - {}", "<other>".bold(), "Repositories listed by a 'git-backup-service-<other>' executable on your PATH");
+    log_info!("    other:jsdw");
+    log_info!("    other+https://git.example.com/jsdw");
+    if plugins.is_empty() {
+        log_info!("    (no service plugins found)");
     } else {
-        None
+        log_info!("    (service plugins found: {})", plugins.join(", "));
     }
 }
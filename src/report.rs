@@ -0,0 +1,115 @@
+use serde::Serialize;
+use std::path::{ Path, PathBuf };
+use crate::error::Error;
+use crate::services::Repository;
+use crate::git::{ PreservedRef, LfsOutcome, CloneStrategy, Verification, Repair, MaintenanceOutcome, SharingOutcome, BundleOutcome };
+
+/// A machine readable summary of a backup, which can be
+/// written out using `--report`.
+#[derive(Debug,Default,Serialize)]
+pub struct Report<'a> {
+    pub sources: Vec<SourceReport<'a>>
+}
+
+#[derive(Debug,Serialize)]
+pub struct SourceReport<'a> {
+    pub url: String,
+    /// Where backups from this source were placed
+    pub destination: Option<PathBuf>,
+    /// Set if we couldn't obtain the repositories for this source
+    pub error: Option<String>,
+    pub repositories: Vec<RepositoryReport<'a>>,
+    /// Submodules that were backed up because repositories
+    /// from this source refer to them
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub submodules: Vec<SubmoduleReport>,
+    /// The names of any backups that were pruned
+    pub pruned: Vec<String>
+}
+
+#[derive(Debug,Serialize)]
+pub struct RepositoryReport<'a> {
+    #[serde(flatten)]
+    pub repository: &'a Repository,
+    /// Where the repository was backed up to
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub status: SyncStatus,
+    /// The errors from earlier attempts to sync the repository,
+    /// which failed for reasons that might not last and were retried
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub retried: Vec<String>,
+    /// How the backup was cloned, if it's not a full clone (and
+    /// so isn't a complete backup)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strategy: Option<CloneStrategy>,
+    /// What happened when fetching LFS objects, if we tried to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lfs: Option<LfsOutcome>,
+    /// What we found when checking the backup, if we did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verification: Option<Verification>,
+    /// What we did about the backup being corrupt, if it was
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repair: Option<Repair>,
+    /// What happened when sharing objects with related backups, if we did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sharing: Option<SharingOutcome>,
+    /// What happened when tidying up the backup, if anything was due
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maintenance: Option<MaintenanceOutcome>,
+    /// What happened when bundling up the backup, if its refs had changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bundle: Option<BundleOutcome>
+}
+
+#[derive(Debug,Serialize)]
+pub struct SubmoduleReport {
+    pub url: String,
+    /// Where the submodule was backed up to
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub status: SyncStatus,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub retried: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lfs: Option<LfsOutcome>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verification: Option<Verification>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repair: Option<Repair>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sharing: Option<SharingOutcome>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maintenance: Option<MaintenanceOutcome>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bundle: Option<BundleOutcome>
+}
+
+/// What happened when we tried to sync a repository?
+#[derive(Debug,Clone,PartialEq,Eq,Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum SyncStatus {
+    Synced {
+        /// Refs that were force-pushed or deleted upstream, and
+        /// where their old history has been kept
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        preserved: Vec<PreservedRef>
+    },
+    /// Nothing had changed upstream since the last backup, so
+    /// nothing was fetched
+    Unchanged,
+    /// Nothing was done because this was a dry run
+    Skipped,
+    /// The repository was not backed up because of a filter
+    Excluded { reason: String },
+    Failed { error: String }
+}
+
+impl <'a> Report<'a> {
+    pub fn write(&self, path: &Path) -> Result<(),Error> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)
+            .map_err(|e| err!("Could not write report to '{}': {}", path.to_string_lossy(), e))
+    }
+}
@@ -1,7 +1,7 @@
 use regex::Regex;
 use lazy_static::lazy_static;
 use crate::error::Error;
-use super::service::{ Service, Repository };
+use super::service::{ Service, Repository, Visibility, explicit_owner, json_string, parse_timestamp };
 
 pub struct Bitbucket {
     /// Which user are we backing up repositories for?
@@ -26,6 +26,14 @@ impl Bitbucket {
 
         Some(Bitbucket { owner, token })
     }
+    /// Create an instance given a URL that we know belongs to this service,
+    /// which can also just be the owner of the repositories (eg `jsdw`).
+    pub fn new_explicit(url: String, token: String) -> Option<Bitbucket> {
+        match explicit_owner(&url) {
+            Some(owner) => Some(Bitbucket { owner, token }),
+            None => Bitbucket::new(url, token)
+        }
+    }
     #[cfg(test)]
     pub fn owner(&self) -> &str {
         &self.owner
@@ -41,7 +49,7 @@ impl Service for Bitbucket {
         let token = &self.token;
         let client = reqwest::Client::new();
         let mut maybe_url: Option<String> = Some(
-            format!("https://api.bitbucket.org/2.0/repositories/{user}?fields=next,values.slug,values.scm,values.links.clone,values.is_private,values.owner.nickname&role=owner", user=self.owner)
+            format!("https://api.bitbucket.org/2.0/repositories/{user}?fields=next,values.uuid,values.slug,values.scm,values.links.clone,values.is_private,values.owner.nickname,values.description,values.language,values.mainbranch.name,values.parent.uuid,values.size,values.updated_on&role=owner", user=self.owner)
         );
         let empty = vec![];
         let mut repos = vec![];
@@ -84,16 +92,31 @@ impl Service for Bitbucket {
                 // Extract the name and URL from the JSON:
                 let name = repo["slug"].as_str().ok_or_else(|| err!("Invalid repo name"))?;
                 let clone = repo["links"]["clone"].as_array().ok_or_else(|| err!("Can't get repo URL"))?;
-                let url = clone.into_iter()
+                let url = clone.iter()
                     .find(|val| val["name"].as_str() == Some("https"))
                     .ok_or_else(|| err!("Can't find HTTPS repo URL to clone from"))?
                     ["href"].as_str()
                     .ok_or_else(|| err!("Invalid clone URL"))?;
+                let ssh_url = clone.iter()
+                    .find(|val| val["name"].as_str() == Some("ssh"))
+                    .and_then(|val| json_string(&val["href"]));
 
                 // Push to our repo list:
                 repos.push(Repository {
-                    name: name.to_owned(),
-                    git_url: url.to_owned()
+                    id: json_string(&repo["uuid"]),
+                    owner: json_string(&repo["owner"]["nickname"]),
+                    description: json_string(&repo["description"]),
+                    language: json_string(&repo["language"]),
+                    default_branch: json_string(&repo["mainbranch"]["name"]),
+                    visibility: repo["is_private"].as_bool().map(Visibility::from_is_private),
+                    is_fork: repo["parent"].is_object(),
+                    size: repo["size"].as_u64(),
+                    // Bitbucket doesn't tell us when the last push was, so
+                    // this is the best we can do:
+                    pushed_at: parse_timestamp(&repo["updated_on"]),
+                    https_url: Some(url.to_owned()),
+                    ssh_url,
+                    ..Repository::new(name.to_owned(), url.to_owned())
                 })
             }
         }
@@ -0,0 +1,257 @@
+use regex::Regex;
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::process::{ Command, Stdio };
+use crate::error::Error;
+use super::service::{ Service, Repository, Credentials, Visibility, explicit_owner, json_string, json_strings, parse_timestamp };
+
+/// A service implemented by an external executable called
+/// `git-backup-service-<name>`, which is handed the source URL
+/// (and the token via the GIT_TOKEN env var) and prints a JSON
+/// list of repositories to stdout.
+pub struct External {
+    /// The name of the service, used to find the executable
+    name: String,
+    /// Which user are we backing up repositories for?
+    owner: String,
+    /// The source URL we were given
+    url: String,
+    /// An access token
+    token: String,
+    /// The executable that we'll call to list repositories
+    program: PathBuf
+}
+
+impl External {
+    pub fn new(url: String, token: String) -> Option<External> {
+        let (name, owner) = parse_source(&url)?;
+        let program = find_program(&program_name(&name))?;
+        Some(External { name, owner, url, token, program })
+    }
+    /// Is there a service plugin with the given name?
+    pub fn exists(name: &str) -> bool {
+        find_program(&program_name(name)).is_some()
+    }
+    /// Create an instance given the name of the service plugin to use
+    /// and a source URL to hand to it.
+    pub fn new_explicit(name: String, url: String, token: String) -> Option<External> {
+        let owner = explicit_owner(&url)
+            .or_else(|| parse_source(&url).map(|(_, owner)| owner))
+            .unwrap_or_default();
+        let program = find_program(&program_name(&name))?;
+        Some(External { name, owner, url, token, program })
+    }
+}
+
+impl Service for External {
+    fn username(&self) -> String {
+        self.owner.to_owned()
+    }
+    fn list_repositories(&self) -> Result<Vec<Repository>,Error> {
+
+        // Hand the URL to the plugin, and let any errors it logs
+        // go straight to our stderr:
+        let output = Command::new(&self.program)
+            .arg(&self.url)
+            .env("GIT_TOKEN", &self.token)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()
+            .map_err(|e| err!("Could not run '{}': {}", self.program.to_string_lossy(), e))?;
+
+        if !output.status.success() {
+            return Err(err!("The '{}' service plugin did not complete successfully ({})", self.name, output.status));
+        }
+
+        let data: serde_json::Value = serde_json::from_slice(&output.stdout)
+            .map_err(|e| err!("Invalid JSON response from the '{}' service plugin: {}", self.name, e))?;
+
+        parse_repositories(&data)
+    }
+}
+
+/// Split a source URL into the name of the service and the owner
+/// whose repositories we want, using the same sorts of formats that
+/// the built in services accept (eg `foo.com/jsdw` or `jsdw@foo`).
+fn parse_source(url: &str) -> Option<(String,String)> {
+    lazy_static! {
+        static ref HTTP_URL_RE: Regex = Regex::new("^(?:http(?:s)?://)?(?:www\\.)?([a-zA-Z0-9_-]+)(?:\\.[^/:@]+)?/([^/]+)(?:/)?$").unwrap();
+        static ref SSH_URL_RE: Regex = Regex::new("^(?:git@)?([a-zA-Z0-9_-]+)(?:\\.[^/:@]+)?:([^/.]+)(?:/)?$").unwrap();
+        static ref BASIC_SSH_RE: Regex = Regex::new("^([^@]+)@([a-zA-Z0-9_-]+)(?:\\.[^/:@]+)?(?:/)?$").unwrap();
+    }
+
+    if let Some(caps) = HTTP_URL_RE.captures(url).or_else(|| SSH_URL_RE.captures(url)) {
+        Some((caps[1].to_lowercase(), caps[2].to_owned()))
+    } else {
+        BASIC_SSH_RE.captures(url).map(|caps| (caps[2].to_lowercase(), caps[1].to_owned()))
+    }
+}
+
+fn program_name(name: &str) -> String {
+    format!("git-backup-service-{}", name)
+}
+
+/// The names of any service plugins that we can find on our PATH.
+pub fn installed_plugins() -> Vec<String> {
+    let prefix = program_name("");
+    let paths = std::env::var_os("PATH").unwrap_or_default();
+    let mut names: Vec<String> = std::env::split_paths(&paths)
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.filter_map(|e| e.ok()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter_map(|file_name| file_name.strip_prefix(&prefix).map(|name| name.to_owned()))
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Look for an executable with the name given on our PATH.
+fn find_program(program: &str) -> Option<PathBuf> {
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+/// Convert the JSON handed back from a plugin into our Repository list.
+/// We expect something like:
+///
+/// ```text
+/// [{ "name": "foo", "git_url": "https://...", "username": "bar",
+///    "password": "wibble", "metadata": { "key": "value" } }]
+/// ```
+///
+/// Where everything but `name` and `git_url` is optional. The other
+/// fields of a Repository (eg `description`, `is_archived` or `pushed_at`)
+/// can also be provided.
+fn parse_repositories(data: &serde_json::Value) -> Result<Vec<Repository>,Error> {
+
+    let repo_values = data.as_array().ok_or_else(|| err!("Expected a JSON list of repositories"))?;
+    let mut repos = vec![];
+
+    for repo in repo_values {
+
+        let name = repo["name"].as_str().ok_or_else(|| err!("Invalid repo name: {:?}", repo["name"]))?;
+        let url = repo["git_url"].as_str().ok_or_else(|| err!("Invalid repo URL: {:?}", repo["git_url"]))?;
+
+        // Credentials are optional, but if a password is given we need
+        // a username to go with it:
+        let credentials = match (&repo["username"], &repo["password"]) {
+            (serde_json::Value::Null, serde_json::Value::Null) => None,
+            (username, password) => Some(Credentials {
+                username: username.as_str().ok_or_else(|| err!("Invalid username for repo '{}'", name))?.to_owned(),
+                password: password.as_str().ok_or_else(|| err!("Invalid password for repo '{}'", name))?.to_owned()
+            })
+        };
+
+        // Metadata values are stored as strings; anything that isn't a
+        // string is stored as its JSON representation:
+        let mut metadata = BTreeMap::new();
+        if let Some(values) = repo["metadata"].as_object() {
+            for (key, value) in values {
+                let value = match value {
+                    serde_json::Value::String(s) => s.to_owned(),
+                    other => other.to_string()
+                };
+                metadata.insert(key.to_owned(), value);
+            }
+        }
+
+        // IDs are often numbers, but we store them as strings:
+        let id = match &repo["id"] {
+            serde_json::Value::Number(n) => Some(n.to_string()),
+            other => json_string(other)
+        };
+
+        let visibility = match repo["visibility"].as_str() {
+            Some(v) => Some(Visibility::parse(v).ok_or_else(|| err!("Invalid visibility for repo '{}': {}", name, v))?),
+            None => None
+        };
+
+        let pushed_at = match &repo["pushed_at"] {
+            serde_json::Value::Null => None,
+            other => Some(parse_timestamp(other).ok_or_else(|| err!("Invalid pushed_at timestamp for repo '{}'", name))?)
+        };
+
+        repos.push(Repository {
+            id,
+            owner: json_string(&repo["owner"]),
+            description: json_string(&repo["description"]),
+            topics: json_strings(&repo["topics"]),
+            language: json_string(&repo["language"]),
+            default_branch: json_string(&repo["default_branch"]),
+            visibility,
+            is_fork: repo["is_fork"].as_bool().unwrap_or(false),
+            is_archived: repo["is_archived"].as_bool().unwrap_or(false),
+            size: repo["size"].as_u64(),
+            pushed_at,
+            https_url: json_string(&repo["https_url"]),
+            ssh_url: json_string(&repo["ssh_url"]),
+            credentials,
+            metadata,
+            ..Repository::new(name.to_owned(), url.to_owned())
+        })
+    }
+
+    Ok(repos)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_valid_urls() {
+        let urls = vec![
+            ("https://gitea.example.com/jsdw", "gitea", "jsdw"),
+            ("http://www.gitea.example.com/jsdw/", "gitea", "jsdw"),
+            ("gitea.example.com/jsdw", "gitea", "jsdw"),
+            ("gitea/jsdw", "gitea", "jsdw"),
+            ("git@gitea.example.com:jsdw", "gitea", "jsdw"),
+            ("gitea:jsdw", "gitea", "jsdw"),
+            ("jsdw@gitea.example.com", "gitea", "jsdw"),
+            ("jsdw@Gitea", "gitea", "jsdw"),
+        ];
+        for (url, name, owner) in urls {
+            assert_eq!(parse_source(url), Some((name.to_owned(), owner.to_owned())), "url {} was not parsed properly", url);
+        }
+    }
+
+    #[test]
+    fn test_parse_repositories() {
+        let data = json!([
+            { "name": "foo", "git_url": "https://example.com/foo.git" },
+            { "name": "bar", "git_url": "https://example.com/bar.git", "username": "u", "password": "p",
+              "metadata": { "stars": 3, "topic": "Bar" } },
+            { "name": "wibble", "git_url": "https://example.com/wibble.git", "id": 1234, "visibility": "private",
+              "is_archived": true, "size": 2048, "pushed_at": "2019-10-01T12:00:00Z", "description": "",
+              "topics": ["prod", "rust"], "language": "Rust" }
+        ]);
+        let repos = parse_repositories(&data).unwrap();
+        assert_eq!(repos.len(), 3);
+        assert_eq!(repos[0], Repository::new("foo".to_owned(), "https://example.com/foo.git".to_owned()));
+        assert_eq!(repos[1].credentials, Some(Credentials { username: "u".to_owned(), password: "p".to_owned() }));
+        assert_eq!(repos[1].metadata.get("stars").map(|s| s.as_str()), Some("3"));
+        assert_eq!(repos[1].metadata.get("topic").map(|s| s.as_str()), Some("Bar"));
+        assert_eq!(repos[2].id.as_deref(), Some("1234"));
+        assert_eq!(repos[2].visibility, Some(Visibility::Private));
+        assert!(repos[2].is_archived);
+        assert_eq!(repos[2].size, Some(2048));
+        assert_eq!(repos[2].pushed_at.map(|t| t.timestamp()), Some(1569931200));
+        assert_eq!(repos[2].description, None);
+        assert_eq!(repos[2].topics, vec!["prod".to_owned(), "rust".to_owned()]);
+        assert_eq!(repos[2].language.as_deref(), Some("Rust"));
+
+        assert!(parse_repositories(&json!({ "name": "foo" })).is_err());
+        assert!(parse_repositories(&json!([{ "name": "foo" }])).is_err());
+        assert!(parse_repositories(&json!([{ "name": "foo", "git_url": "bar", "password": "p" }])).is_err());
+        assert!(parse_repositories(&json!([{ "name": "foo", "git_url": "bar", "visibility": "secret" }])).is_err());
+        assert!(parse_repositories(&json!([{ "name": "foo", "git_url": "bar", "pushed_at": "yesterday" }])).is_err());
+    }
+
+}
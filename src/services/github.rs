@@ -2,7 +2,9 @@ use regex::Regex;
 use serde_json::json;
 use lazy_static::lazy_static;
 use crate::error::Error;
-use super::service::{ Service, Repository };
+use crate::git::RefSelection;
+use crate::filter::{ Expr, Flag };
+use super::service::{ Service, Repository, Visibility, explicit_owner, json_string, parse_timestamp };
 
 pub struct GitHub {
     /// Which user are we backing up repositories for?
@@ -28,6 +30,14 @@ impl GitHub {
 
         Some(GitHub { owner, token })
     }
+    /// Create an instance given a URL that we know belongs to this service,
+    /// which can also just be the owner of the repositories (eg `jsdw`).
+    pub fn new_explicit(url: String, token: String) -> Option<GitHub> {
+        match explicit_owner(&url) {
+            Some(owner) => Some(GitHub { owner, token }),
+            None => GitHub::new(url, token)
+        }
+    }
     #[cfg(test)]
     pub fn owner(&self) -> &str {
         &self.owner
@@ -38,6 +48,15 @@ impl Service for GitHub {
     fn username(&self) -> String {
         self.owner.to_owned()
     }
+    fn default_refs(&self) -> RefSelection {
+        // Every pull request (`refs/pull/*`) has refs of its own, which
+        // would otherwise make up most of what we back up:
+        RefSelection::BranchesAndTags
+    }
+    fn default_filter(&self) -> Option<Expr> {
+        // Forks have only ever been backed up when asked for:
+        Some(Expr::Not(Box::new(Expr::Flag(Flag::Fork))))
+    }
     fn list_repositories(&self) -> Result<Vec<Repository>,Error> {
 
         let token = &self.token;
@@ -91,8 +110,26 @@ impl Service for GitHub {
                 let url = repo["url"].as_str().ok_or_else(|| err!("Invalid repo URL: {:?}", repo["url"]))?;
 
                 repos.push(Repository {
-                    name: name.to_owned(),
-                    git_url: url.to_owned()
+                    id: json_string(&repo["id"]),
+                    owner: json_string(&repo["owner"]["login"]),
+                    description: json_string(&repo["description"]),
+                    topics: repo["repositoryTopics"]["nodes"]
+                        .as_array()
+                        .unwrap_or(&empty)
+                        .iter()
+                        .filter_map(|node| json_string(&node["topic"]["name"]))
+                        .collect(),
+                    language: json_string(&repo["primaryLanguage"]["name"]),
+                    default_branch: json_string(&repo["defaultBranchRef"]["name"]),
+                    visibility: repo["isPrivate"].as_bool().map(Visibility::from_is_private),
+                    is_fork: repo["isFork"].as_bool().unwrap_or(false),
+                    is_archived: repo["isArchived"].as_bool().unwrap_or(false),
+                    // GitHub gives us the size in kilobytes:
+                    size: repo["diskUsage"].as_u64().map(|kb| kb * 1024),
+                    pushed_at: parse_timestamp(&repo["pushedAt"]),
+                    https_url: Some(url.to_owned()),
+                    ssh_url: json_string(&repo["sshUrl"]),
+                    ..Repository::new(name.to_owned(), url.to_owned())
                 })
 
             }
@@ -113,13 +150,37 @@ impl Service for GitHub {
 static GRAPHQL_QUERY: &str = "
     query($user:String!,$cursor:String) {
         user(login:$user) {
-            repositories(first:100,after:$cursor,ownerAffiliations:OWNER,isFork:false) {
+            repositories(first:100,after:$cursor,ownerAffiliations:OWNER) {
                 pageInfo {
                     endCursor
                 }
                 nodes {
-                    url,
+                    id
+                    url
+                    sshUrl
                     name
+                    owner {
+                        login
+                    }
+                    description
+                    repositoryTopics(first:100) {
+                        nodes {
+                            topic {
+                                name
+                            }
+                        }
+                    }
+                    primaryLanguage {
+                        name
+                    }
+                    defaultBranchRef {
+                        name
+                    }
+                    isPrivate
+                    isFork
+                    isArchived
+                    diskUsage
+                    pushedAt
                 }
             }
         }
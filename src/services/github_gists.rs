@@ -3,7 +3,7 @@ use serde_json::json;
 use lazy_static::lazy_static;
 use std::collections::HashMap;
 use crate::error::Error;
-use super::service::{ Service, Repository };
+use super::service::{ Service, Repository, Visibility, explicit_owner, json_string, parse_timestamp };
 
 pub struct GitHubGists {
     /// Which user are we backing up repositories for?
@@ -30,6 +30,14 @@ impl GitHubGists {
 
         Some(GitHubGists { owner, token })
     }
+    /// Create an instance given a URL that we know belongs to this service,
+    /// which can also just be the owner of the repositories (eg `jsdw`).
+    pub fn new_explicit(url: String, token: String) -> Option<GitHubGists> {
+        match explicit_owner(&url) {
+            Some(owner) => Some(GitHubGists { owner, token }),
+            None => GitHubGists::new(url, token)
+        }
+    }
     #[cfg(test)]
     pub fn owner(&self) -> &str {
         &self.owner
@@ -89,14 +97,19 @@ impl Service for GitHubGists {
             let data = &data["data"]["user"]["gists"];
             let this_repos = data["nodes"].as_array().unwrap_or(&empty);
             for repo in this_repos {
-
-               println!("Repo: {:?}", repo);
                 let url = repo["url"].as_str().ok_or_else(|| err!("Invalid gist URL: {:?}", repo["url"]))?;
                 let name = repo["files"][0]["name"].as_str().ok_or_else(|| err!("Invalid gist name"))?;
 
                 repos.push(Repository {
-                    name: name.to_owned(),
-                    git_url: url.to_owned()
+                    id: json_string(&repo["name"]),
+                    owner: json_string(&repo["owner"]["login"]),
+                    description: json_string(&repo["description"]),
+                    visibility: repo["isPublic"].as_bool().map(|is_public| Visibility::from_is_private(!is_public)),
+                    is_fork: repo["isFork"].as_bool().unwrap_or(false),
+                    pushed_at: parse_timestamp(&repo["pushedAt"]),
+                    https_url: Some(url.to_owned()),
+                    ssh_url: repo["name"].as_str().map(|id| format!("git@gist.github.com:{}.git", id)),
+                    ..Repository::new(name.to_owned(), url.to_owned())
                 })
             }
 
@@ -133,7 +146,15 @@ static GRAPHQL_QUERY: &str = "
         user(login: $user) {
             gists(first: 100, after: $cursor, privacy:ALL, orderBy: { field:CREATED_AT, direction:ASC }) {
                 nodes {
+                    name
                     url
+                    owner {
+                        login
+                    }
+                    description
+                    isPublic
+                    isFork
+                    pushedAt
                     createdAt
                     files(limit: 1) {
                         name
@@ -1,9 +1,12 @@
 use regex::Regex;
 use lazy_static::lazy_static;
 use crate::error::Error;
-use super::service::{ Service, Repository };
+use crate::git::RefSelection;
+use super::service::{ Service, Repository, Visibility, explicit_owner, json_string, json_strings, parse_timestamp };
 
 pub struct GitLab {
+    /// Where is GitLab hosted (eg `https://gitlab.com`)?
+    base_url: String,
     /// Which user are we backing up repositories for?
     owner: String,
     /// An access token
@@ -24,24 +27,50 @@ impl GitLab {
 
         let owner = caps.get(1).unwrap().as_str().to_owned();
 
-        Some(GitLab { owner, token })
+        Some(GitLab { base_url: DEFAULT_BASE_URL.to_owned(), owner, token })
+    }
+    /// Create an instance given a URL that we know belongs to a GitLab instance,
+    /// which may be self-hosted (eg `https://git.example.com/jsdw`), or just
+    /// the owner of some repositories on gitlab.com.
+    pub fn new_explicit(url: String, token: String) -> Option<GitLab> {
+        lazy_static! {
+            static ref HOST_URL_RE: Regex = Regex::new("^(http(?:s)?://)?([^/@:]+(?::[0-9]+)?)/([^/]+)(?:/)?$").unwrap();
+        }
+        if let Some(owner) = explicit_owner(&url) {
+            return Some(GitLab { base_url: DEFAULT_BASE_URL.to_owned(), owner, token })
+        }
+
+        let caps = HOST_URL_RE.captures(&url)?;
+        let scheme = caps.get(1).map(|m| m.as_str()).unwrap_or("https://");
+        let host = caps.get(2).unwrap().as_str();
+        let owner = caps.get(3).unwrap().as_str().to_owned();
+
+        Some(GitLab { base_url: format!("{}{}", scheme, host), owner, token })
     }
     #[cfg(test)]
     pub fn owner(&self) -> &str {
         &self.owner
     }
+    #[cfg(test)]
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
 }
 
 impl Service for GitLab {
     fn username(&self) -> String {
         self.owner.to_owned()
     }
+    fn default_refs(&self) -> RefSelection {
+        // Leave out the refs kept for each merge request (`refs/merge-requests/*`):
+        RefSelection::BranchesAndTags
+    }
     fn list_repositories(&self) -> Result<Vec<Repository>,Error> {
 
         let token = &self.token;
         let client = reqwest::Client::new();
 
-        let url = format!("https://gitlab.com/api/v4/users/{user}/projects?simple=true&owned=true", user=self.owner);
+        let url = format!("{base}/api/v4/users/{user}/projects?owned=true&statistics=true", base=self.base_url, user=self.owner);
         let empty = vec![];
         let mut res = client
             .get(&url)
@@ -77,8 +106,22 @@ impl Service for GitLab {
 
             // Push to our repo list:
             repos.push(Repository {
-                name: name.to_owned(),
-                git_url: url.to_owned()
+                id: repo["id"].as_u64().map(|id| id.to_string()),
+                owner: json_string(&repo["namespace"]["full_path"]),
+                description: json_string(&repo["description"]),
+                // Older versions of GitLab call topics tags:
+                topics: if repo["topics"].is_array() { json_strings(&repo["topics"]) } else { json_strings(&repo["tag_list"]) },
+                default_branch: json_string(&repo["default_branch"]),
+                visibility: repo["visibility"].as_str().and_then(Visibility::parse),
+                is_fork: repo["forked_from_project"].is_object(),
+                is_archived: repo["archived"].as_bool().unwrap_or(false),
+                size: repo["statistics"]["repository_size"].as_u64(),
+                // GitLab doesn't tell us when the last push was, so
+                // this is the best we can do:
+                pushed_at: parse_timestamp(&repo["last_activity_at"]),
+                https_url: Some(url.to_owned()),
+                ssh_url: json_string(&repo["ssh_url_to_repo"]),
+                ..Repository::new(name.to_owned(), url.to_owned())
             })
         }
 
@@ -86,6 +129,8 @@ impl Service for GitLab {
     }
 }
 
+static DEFAULT_BASE_URL: &str = "https://gitlab.com";
+
 #[cfg(test)]
 mod test {
 
@@ -119,4 +164,23 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_valid_explicit_urls() {
+        let urls = vec![
+            ("jsdw", "https://gitlab.com", "jsdw"),
+            ("jsdw/", "https://gitlab.com", "jsdw"),
+            ("https://git.example.com/jsdw", "https://git.example.com", "jsdw"),
+            ("http://git.example.com:8080/jsdw/", "http://git.example.com:8080", "jsdw"),
+            ("git.example.com/jsdw", "https://git.example.com", "jsdw"),
+        ];
+        for (url, base_url, owner) in urls {
+            if let Some(gl) = GitLab::new_explicit(url.to_owned(), "token".to_owned()) {
+                assert_eq!(gl.base_url(), base_url, "url {} expected base URL {} but got {}", url, base_url, gl.base_url());
+                assert_eq!(gl.owner(), owner, "url {} expected owner {} but got {}", url, owner, gl.owner());
+            } else {
+                panic!("url {} was not parsed properly", url);
+            }
+        }
+    }
+
 }
\ No newline at end of file
@@ -2,10 +2,164 @@ mod github;
 mod github_gists;
 mod gitlab;
 mod bitbucket;
+mod external;
 mod service;
 
+use regex::Regex;
+use lazy_static::lazy_static;
+use crate::error::Error;
+
 pub use github::GitHub;
 pub use github_gists::GitHubGists;
 pub use gitlab::GitLab;
 pub use bitbucket::Bitbucket;
-pub use service::{ Service, Repository };
+pub use external::{ External, installed_plugins };
+pub use service::{ Service, Repository, Visibility };
+
+/// A service that we know how to back up repositories from.
+pub struct ServiceInfo {
+    /// The name of the service. Sources can be prefixed with this
+    /// (eg `github:jsdw`) to use the service explicitly.
+    pub name: &'static str,
+    /// What does this service back up?
+    pub description: &'static str,
+    /// Some examples of sources that this service accepts.
+    pub examples: &'static [&'static str],
+    /// Create the service if the source looks like it belongs to it.
+    detect: fn(String, String) -> Option<Box<dyn Service>>,
+    /// Create the service from a source that we know belongs to it.
+    explicit: fn(String, String) -> Option<Box<dyn Service>>
+}
+
+/// Every built in service, in the order that we'll try to
+/// match sources against them.
+pub static SERVICES: &[ServiceInfo] = &[
+    ServiceInfo {
+        name: "github",
+        description: "Repositories on GitHub",
+        examples: &["github/jsdw", "https://github.com/jsdw", "git@github.com:jsdw", "github:jsdw"],
+        detect: |url, token| GitHub::new(url, token).map(boxed),
+        explicit: |url, token| GitHub::new_explicit(url, token).map(boxed)
+    },
+    ServiceInfo {
+        name: "gist",
+        description: "Gists on GitHub",
+        examples: &["gist.github/jsdw", "https://gist.github.com/jsdw", "gist:jsdw"],
+        detect: |url, token| GitHubGists::new(url, token).map(boxed),
+        explicit: |url, token| GitHubGists::new_explicit(url, token).map(boxed)
+    },
+    ServiceInfo {
+        name: "bitbucket",
+        description: "Repositories on Bitbucket",
+        examples: &["bitbucket/jsdw", "https://bitbucket.org/jsdw", "bitbucket:jsdw"],
+        detect: |url, token| Bitbucket::new(url, token).map(boxed),
+        explicit: |url, token| Bitbucket::new_explicit(url, token).map(boxed)
+    },
+    ServiceInfo {
+        name: "gitlab",
+        description: "Repositories on gitlab.com or a self-hosted GitLab instance",
+        examples: &["gitlab/jsdw", "https://gitlab.org/jsdw", "gitlab:jsdw", "gitlab+https://git.example.com/jsdw"],
+        detect: |url, token| GitLab::new(url, token).map(boxed),
+        explicit: |url, token| GitLab::new_explicit(url, token).map(boxed)
+    },
+];
+
+fn boxed<S: Service + 'static>(service: S) -> Box<dyn Service> {
+    Box::new(service)
+}
+
+/// Find the service that a source belongs to. The service can be given
+/// explicitly, else the source can be prefixed with the service name (eg
+/// `github:jsdw` or `gitlab+https://git.example.com/jsdw`), else we try each
+/// service in turn, and finally look for a service plugin.
+pub fn pick_service(url: String, token: String, service_name: Option<&str>) -> Result<Box<dyn Service>,Error> {
+
+    if let Some(name) = service_name {
+        return explicit_service(name, url, token)
+    }
+
+    if let Some((name, rest, is_plus)) = split_prefix(&url) {
+        let service = explicit_service(&name, rest, token.clone());
+        // 'name+url' is always an explicit service, so return any error:
+        if service.is_ok() || is_plus {
+            return service
+        }
+    }
+
+    SERVICES.iter()
+        .find_map(|info| (info.detect)(url.clone(), token.clone()))
+        .or_else(|| External::new(url.clone(), token).map(boxed))
+        .ok_or_else(|| err!("Source '{}' not recognised", url))
+}
+
+/// Look up a service (or failing that, a service plugin) by name.
+fn explicit_service(name: &str, url: String, token: String) -> Result<Box<dyn Service>,Error> {
+    let service = match SERVICES.iter().find(|info| info.name == name) {
+        Some(info) => (info.explicit)(url.clone(), token),
+        None => {
+            if !External::exists(name) {
+                return Err(err!("Service '{}' not recognised; see the 'services' command for those available", name))
+            }
+            External::new_explicit(name.to_owned(), url.clone(), token).map(boxed)
+        }
+    };
+    service.ok_or_else(|| err!("Source '{}' is not valid for the service '{}'", url, name))
+}
+
+/// Split a source like `github:jsdw` or `gitlab+https://git.example.com/jsdw`
+/// into the service name, the rest of the source, and whether a '+' was used.
+fn split_prefix(url: &str) -> Option<(String,String,bool)> {
+    lazy_static! {
+        static ref PREFIX_RE: Regex = Regex::new("^([a-zA-Z0-9_-]+)(\\+|:)(.+)$").unwrap();
+    }
+    let caps = PREFIX_RE.captures(url)?;
+    let name = caps[1].to_lowercase();
+    let is_plus = &caps[2] == "+";
+    // 'https://foo' isn't a service called 'https':
+    if !is_plus && caps[3].starts_with("//") {
+        return None
+    }
+    Some((name, caps[3].to_owned(), is_plus))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_split_prefix() {
+        let valid = vec![
+            ("github:jsdw", "github", "jsdw", false),
+            ("GitLab:jsdw", "gitlab", "jsdw", false),
+            ("gitlab+https://git.example.com/jsdw", "gitlab", "https://git.example.com/jsdw", true),
+            ("gitea+git.example.com/jsdw", "gitea", "git.example.com/jsdw", true),
+        ];
+        for (url, name, rest, is_plus) in valid {
+            assert_eq!(split_prefix(url), Some((name.to_owned(), rest.to_owned(), is_plus)), "url {} was not split properly", url);
+        }
+
+        let invalid = vec![
+            "https://github.com/jsdw",
+            "git@github.com:jsdw",
+            "github.com:jsdw",
+            "github/jsdw",
+            "jsdw@github",
+        ];
+        for url in invalid {
+            assert_eq!(split_prefix(url), None, "url {} should not have a prefix", url);
+        }
+    }
+
+    #[test]
+    fn test_examples_are_valid() {
+        for info in SERVICES {
+            for example in info.examples {
+                assert!(pick_service(example.to_string(), "token".to_owned(), None).is_ok(), "example {} is not valid", example);
+                let rest = split_prefix(example).map(|(_, rest, _)| rest).unwrap_or_else(|| example.to_string());
+                assert!(pick_service(rest, "token".to_owned(), Some(info.name)).is_ok(), "example {} is not valid for --service {}", example, info.name);
+            }
+        }
+    }
+
+}
@@ -1,14 +1,146 @@
+use std::collections::BTreeMap;
+use regex::Regex;
+use serde::Serialize;
+use chrono::{ DateTime, Utc };
+use lazy_static::lazy_static;
 use crate::error::Error;
+use crate::git::RefSelection;
+use crate::filter::Expr;
 
 pub trait Service {
     /// What is our username for this service?
     fn username(&self) -> String;
     /// Which repositories do we want to back up?
     fn list_repositories(&self) -> Result<Vec<Repository>,Error>;
+    /// Which refs should we back up, if we haven't been told?
+    fn default_refs(&self) -> RefSelection {
+        RefSelection::All
+    }
+    /// Which repositories should we leave out, unless the filter that
+    /// we've been given says something about the same flags?
+    fn default_filter(&self) -> Option<Expr> {
+        None
+    }
 }
 
-#[derive(Clone,Debug,PartialEq,Eq)]
+#[derive(Clone,Debug,PartialEq,Eq,Serialize)]
 pub struct Repository {
+    /// The URL that we'll clone the repository from
     pub git_url: String,
-    pub name: String
-}
\ No newline at end of file
+    /// The name of the repository, which its backup is named after
+    pub name: String,
+    /// An ID for the repository that the service won't change
+    /// if the repository is renamed
+    pub id: Option<String>,
+    /// The user or namespace that the repository belongs to
+    pub owner: Option<String>,
+    pub description: Option<String>,
+    /// Topics (or tags) that the repository has been labelled with
+    pub topics: Vec<String>,
+    /// The main language that the repository is written in
+    pub language: Option<String>,
+    pub default_branch: Option<String>,
+    pub visibility: Option<Visibility>,
+    pub is_fork: bool,
+    pub is_archived: bool,
+    /// The approximate size of the repository in bytes
+    pub size: Option<u64>,
+    /// When the repository was last pushed to. Some services
+    /// only tell us when the repository was last updated.
+    pub pushed_at: Option<DateTime<Utc>>,
+    /// The URL to clone the repository from over HTTPS
+    pub https_url: Option<String>,
+    /// The URL to clone the repository from over SSH
+    pub ssh_url: Option<String>,
+    /// Credentials to use for this repository in place of
+    /// the service username and token, if provided.
+    #[serde(skip)]
+    pub credentials: Option<Credentials>,
+    /// Any additional information the service has about
+    /// the repository.
+    pub metadata: BTreeMap<String,String>
+}
+
+impl Repository {
+    /// A repository that we know nothing else about
+    /// besides its name and where to clone it from.
+    pub fn new(name: String, git_url: String) -> Repository {
+        Repository {
+            git_url,
+            name,
+            id: None,
+            owner: None,
+            description: None,
+            topics: vec![],
+            language: None,
+            default_branch: None,
+            visibility: None,
+            is_fork: false,
+            is_archived: false,
+            size: None,
+            pushed_at: None,
+            https_url: None,
+            ssh_url: None,
+            credentials: None,
+            metadata: BTreeMap::new()
+        }
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    Public,
+    /// Visible to anybody logged in to the service
+    Internal,
+    Private
+}
+
+impl Visibility {
+    pub fn parse(s: &str) -> Option<Visibility> {
+        match &*s.to_lowercase() {
+            "public" => Some(Visibility::Public),
+            "internal" => Some(Visibility::Internal),
+            "private" => Some(Visibility::Private),
+            _ => None
+        }
+    }
+    pub fn from_is_private(is_private: bool) -> Visibility {
+        if is_private { Visibility::Private } else { Visibility::Public }
+    }
+}
+
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String
+}
+
+/// When a service is picked explicitly, the source can just be the
+/// owner of the repositories (eg `jsdw` in `github:jsdw`). Parse that
+/// owner out if so.
+pub fn explicit_owner(url: &str) -> Option<String> {
+    lazy_static! {
+        static ref OWNER_RE: Regex = Regex::new("^([^/@:.]+)(?:/)?$").unwrap();
+    }
+    OWNER_RE.captures(url).map(|caps| caps[1].to_owned())
+}
+
+/// Services hand back RFC 3339 timestamps (eg `2019-10-01T12:00:00Z`);
+/// parse them if we can.
+pub fn parse_timestamp(value: &serde_json::Value) -> Option<DateTime<Utc>> {
+    let s = value.as_str()?;
+    DateTime::parse_from_rfc3339(s).ok().map(|d| d.with_timezone(&Utc))
+}
+
+/// Get a list of strings from some JSON value.
+pub fn json_strings(value: &serde_json::Value) -> Vec<String> {
+    value.as_array()
+        .map(|values| values.iter().filter_map(json_string).collect())
+        .unwrap_or_default()
+}
+
+/// Get a string from some JSON value, if it's a non-empty one.
+pub fn json_string(value: &serde_json::Value) -> Option<String> {
+    value.as_str().filter(|s| !s.is_empty()).map(|s| s.to_owned())
+}
@@ -0,0 +1,313 @@
+use std::str::FromStr;
+use std::path::PathBuf;
+use std::process::{ Command, Stdio };
+use regex::Regex;
+use serde::Deserialize;
+use lazy_static::lazy_static;
+use crate::error::Error;
+use crate::filter::{ Filter, Pattern, Expr };
+use crate::git::RefSelection;
+use crate::{ QUARANTINE_FOLDER, OBJECT_POOLS_FOLDER, EXTERNAL_FOLDER };
+
+/// A source of repositories to back up, as provided via `--source`
+/// or in a config file.
+#[derive(Debug,Clone,PartialEq,Eq,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Source {
+    /// URL of the repositories to back up
+    pub url: String,
+    /// Where to obtain the token for this source from, if
+    /// not the token we've been given by default
+    pub token: Option<Token>,
+    /// The folder to place backups from this source in
+    pub name: Option<String>,
+    /// The service that this source belongs to, if it can't be
+    /// worked out from the URL
+    pub service: Option<String>,
+    /// A location to place backups from this source, instead of
+    /// a folder in the main destination
+    pub destination: Option<PathBuf>,
+    /// Whether to prune backups from this source, if not the default
+    pub prune: Option<bool>,
+    /// Only back up repositories matching one of these, in
+    /// addition to any patterns given for every source
+    #[serde(default)]
+    pub include: Vec<Pattern>,
+    /// Don't back up repositories matching any of these, in
+    /// addition to any patterns given for every source
+    #[serde(default)]
+    pub exclude: Vec<Pattern>,
+    /// Only back up repositories for which this is true, in
+    /// addition to any filter given for every source
+    pub filter: Option<Expr>,
+    /// Clone repositories over SSH using this private key,
+    /// if not the key we've been given by default
+    pub ssh_key: Option<PathBuf>,
+    /// The known_hosts file to check hosts against when
+    /// cloning over SSH, if not the default one
+    pub known_hosts: Option<PathBuf>,
+    /// Which refs to back up, if not the default for the service
+    pub refs: Option<RefSelection>,
+    /// Whether to fetch LFS objects, if not the default
+    pub lfs: Option<bool>,
+    /// Whether to back up submodules, if not the default
+    pub submodules: Option<bool>,
+    /// Whether to run maintenance on backups, if not the default
+    pub maintenance: Option<bool>
+}
+
+impl Source {
+    pub fn new(url: String) -> Source {
+        Source {
+            url,
+            token: None,
+            name: None,
+            service: None,
+            destination: None,
+            prune: None,
+            include: vec![],
+            exclude: vec![],
+            filter: None,
+            ssh_key: None,
+            known_hosts: None,
+            refs: None,
+            lfs: None,
+            submodules: None,
+            maintenance: None
+        }
+    }
+    /// The name of the folder that backups from this source
+    /// should be placed into.
+    pub fn folder_name(&self) -> String {
+        lazy_static! {
+            static ref PREFIX_RE: Regex = Regex::new("^(?:http(?:s)?://)?(?:www\\.)?").unwrap();
+            static ref INVALID_CHARS_RE: Regex = Regex::new("[^a-zA-Z0-9_.-]+").unwrap();
+        }
+        if let Some(name) = &self.name {
+            return name.to_owned()
+        }
+        let url = PREFIX_RE.replace(&self.url, "");
+        INVALID_CHARS_RE.replace_all(&url, "-").trim_matches(|c| c == '-' || c == '.').to_owned()
+    }
+    /// The patterns given for this source.
+    pub fn filter(&self) -> Filter {
+        Filter {
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            expr: self.filter.clone()
+        }
+    }
+    /// Check that the source makes sense.
+    pub fn validate(&self) -> Result<(),Error> {
+        if self.url.trim().is_empty() {
+            return Err(err!("The source url cannot be empty"))
+        }
+        let folder = self.folder_name();
+        if folder.is_empty() || folder == "." || folder == ".." || folder.contains('/') || folder.contains('\\') {
+            return Err(err!("'{}' is not a valid folder name", folder))
+        }
+        // These live alongside the folders that sources are backed up into:
+        if [QUARANTINE_FOLDER, OBJECT_POOLS_FOLDER, EXTERNAL_FOLDER].iter().any(|reserved| folder.eq_ignore_ascii_case(reserved)) {
+            return Err(err!("The folder name '{}' is used for something else; use 'name=<folder>' to give this source another", folder))
+        }
+        if let Some(token) = &self.token {
+            token.validate()?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Source {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Source,String> {
+
+        let mut url = None;
+        let mut token = None;
+        let mut name = None;
+        let mut service = None;
+        let mut ssh_key = None;
+        let mut known_hosts = None;
+        let mut refs = None;
+
+        for part in s.split(',') {
+            let mut kv = part.splitn(2, '=');
+            let key = kv.next().unwrap_or("").trim();
+            let value = kv.next()
+                .ok_or_else(|| format!("Expected 'key=value' but got '{}'", part))?
+                .trim()
+                .to_owned();
+
+            if value.is_empty() {
+                return Err(format!("No value provided for '{}'", key))
+            }
+
+            match key {
+                "url" => url = Some(value),
+                "token" => token = Some(Token::Value(value)),
+                "token-env" => token = Some(Token::Env(value)),
+                "token-file" => token = Some(Token::File(value.into())),
+                "token-cmd" => token = Some(Token::Command(value)),
+                "name" => name = Some(value),
+                "service" => service = Some(value),
+                "ssh-key" => ssh_key = Some(value.into()),
+                "known-hosts" => known_hosts = Some(value.into()),
+                "refs" => refs = Some(value.parse().map_err(|e| format!("Invalid refs: {}", e))?),
+                _ => return Err(format!("Unknown key '{}'; expected one of url, token, token-env, token-file, token-cmd, name, service, ssh-key, known-hosts or refs", key))
+            }
+        }
+
+        let url = url.ok_or_else(|| "A url must be provided, eg 'url=github/jsdw'".to_owned())?;
+        let source = Source { token, name, service, ssh_key, known_hosts, refs, ..Source::new(url) };
+        source.validate().map_err(|e| e.to_string())?;
+        Ok(source)
+    }
+}
+
+/// Where can we find the token for a source?
+#[derive(Debug,Clone,PartialEq,Eq,Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum Token {
+    /// The token itself
+    Value(String),
+    /// The name of an environment variable containing the token
+    Env(String),
+    /// A file containing the token
+    File(PathBuf),
+    /// A shell command that prints the token
+    Command(String)
+}
+
+impl Token {
+    pub fn resolve(&self) -> Result<String,Error> {
+        let token = match self {
+            Token::Value(token) => token.to_owned(),
+            Token::Env(var) => std::env::var(var)
+                .map_err(|_| err!("The environment variable '{}' does not contain a token", var))?,
+            Token::File(path) => std::fs::read_to_string(path)
+                .map_err(|e| err!("Could not read token from '{}': {}", path.to_string_lossy(), e))?,
+            Token::Command(cmd) => {
+                let output = Command::new("sh")
+                    .arg("-c").arg(cmd)
+                    .stdin(Stdio::null())
+                    .stderr(Stdio::inherit())
+                    .output()
+                    .map_err(|e| err!("Could not run token command '{}': {}", cmd, e))?;
+                if !output.status.success() {
+                    return Err(err!("The token command '{}' did not complete successfully ({})", cmd, output.status))
+                }
+                String::from_utf8(output.stdout)
+                    .map_err(|_| err!("The token command '{}' did not output valid UTF-8", cmd))?
+            }
+        };
+        // Files and commands often come with a trailing newline:
+        let token = token.trim();
+        if token.is_empty() {
+            return Err(err!("The token is empty"))
+        }
+        Ok(token.to_owned())
+    }
+    /// Check that the token looks sensible before we try to use it.
+    pub fn validate(&self) -> Result<(),Error> {
+        let empty = match self {
+            Token::Value(s) | Token::Env(s) | Token::Command(s) => s.trim().is_empty(),
+            Token::File(path) => path.as_os_str().is_empty()
+        };
+        if empty {
+            Err(err!("The token {} cannot be empty", match self {
+                Token::Value(_) => "value",
+                Token::Env(_) => "environment variable",
+                Token::File(_) => "file",
+                Token::Command(_) => "command"
+            }))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_source() {
+        assert_eq!("url=github/jsdw".parse(), Ok(Source::new("github/jsdw".to_owned())));
+        assert_eq!("url=gitlab/jsdw,token-env=GITLAB_TOKEN,name=gitlab".parse(), Ok(Source {
+            token: Some(Token::Env("GITLAB_TOKEN".to_owned())),
+            name: Some("gitlab".to_owned()),
+            ..Source::new("gitlab/jsdw".to_owned())
+        }));
+        assert_eq!("name=foo, url=github/jsdw, token=abc=".parse(), Ok(Source {
+            token: Some(Token::Value("abc=".to_owned())),
+            name: Some("foo".to_owned()),
+            ..Source::new("github/jsdw".to_owned())
+        }));
+        assert_eq!("url=github/jsdw,token-file=/tmp/token".parse(), Ok(Source {
+            token: Some(Token::File("/tmp/token".into())),
+            ..Source::new("github/jsdw".to_owned())
+        }));
+        assert_eq!("url=git.example.com/jsdw,service=gitlab".parse(), Ok(Source {
+            service: Some("gitlab".to_owned()),
+            ..Source::new("git.example.com/jsdw".to_owned())
+        }));
+        assert_eq!("url=github/jsdw,token-cmd=pass show github".parse(), Ok(Source {
+            token: Some(Token::Command("pass show github".to_owned())),
+            ..Source::new("github/jsdw".to_owned())
+        }));
+        assert_eq!("url=github/work,ssh-key=/keys/deploy,known-hosts=/keys/known_hosts".parse(), Ok(Source {
+            ssh_key: Some("/keys/deploy".into()),
+            known_hosts: Some("/keys/known_hosts".into()),
+            ..Source::new("github/work".to_owned())
+        }));
+        assert_eq!("url=github/jsdw,refs=default-branch".parse(), Ok(Source {
+            refs: Some(RefSelection::DefaultBranch),
+            ..Source::new("github/jsdw".to_owned())
+        }));
+
+        let invalid = vec![
+            "",
+            "github/jsdw",
+            "token=abc",
+            "url=github/jsdw,foo=bar",
+            "url=github/jsdw,name=",
+            "url=github/jsdw,name=..",
+            "url=github/jsdw,name=foo/bar",
+            "url=github/jsdw,name=objects-pool",
+            "url=github/jsdw,name=Quarantine",
+            "url=external",
+            "url=---",
+            "url=github/jsdw,refs=pulls",
+        ];
+        for s in invalid {
+            assert!(s.parse::<Source>().is_err(), "'{}' should not be a valid source", s);
+        }
+    }
+
+    #[test]
+    fn test_folder_name() {
+        let names = vec![
+            ("github/jsdw", "github-jsdw"),
+            ("https://www.github.com/jsdw/", "github.com-jsdw"),
+            ("git@gitlab.org:jsdw", "git-gitlab.org-jsdw"),
+            ("jsdw@bitbucket", "jsdw-bitbucket"),
+        ];
+        for (url, name) in names {
+            assert_eq!(Source::new(url.to_owned()).folder_name(), name);
+        }
+        let mut named = Source::new("github/jsdw".to_owned());
+        named.name = Some("mine".to_owned());
+        assert_eq!(named.folder_name(), "mine");
+    }
+
+    #[test]
+    fn test_resolve_token() {
+        assert_eq!(Token::Value("abc".to_owned()).resolve().unwrap(), "abc");
+        assert_eq!(Token::Command("echo ' abc '".to_owned()).resolve().unwrap(), "abc");
+        assert!(Token::Command("exit 1".to_owned()).resolve().is_err());
+        assert!(Token::Command("true".to_owned()).resolve().is_err());
+        assert!(Token::Env("GIT_BACKUP_TEST_DOES_NOT_EXIST".to_owned()).resolve().is_err());
+        assert!(Token::File("/git-backup/does/not/exist".into()).resolve().is_err());
+    }
+
+}